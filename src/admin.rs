@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CacheStats;
+
+/// State of the scans of the served directory.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScanStatus {
+    /// A scan is running right now.
+    pub scanning: bool,
+    /// Local time the last successful scan finished, in RFC 3339.
+    pub last_scan_at: String,
+    pub last_duration_ms: u64,
+    /// Error of the last scan, cleared by the next successful one.
+    pub last_error: Option<String>,
+    /// Entries the last scan skipped.
+    pub skipped: usize,
+}
+
+/// Answer to `Request::AdminStatus`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub dir: String,
+    /// Counts the scans whose result is served, starting at 1. A client seeing
+    /// the same generation twice saw the same `DirInfo`.
+    pub generation: u64,
+    pub files: usize,
+    /// Distinct contents among `files`.
+    pub blobs: usize,
+    pub scan: ScanStatus,
+    pub cache: CacheStats,
+    pub clients: usize,
+}
+
+/// A connected client, answered to `Request::AdminClients`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub peer: String,
+    /// Name sent with `Auth`, empty before it.
+    pub name: String,
+    pub connected_secs: u64,
+    pub requests: u64,
+    /// The request being answered, with the file for downloads.
+    pub current: Option<String>,
+}
@@ -0,0 +1,552 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::common::tmp_path;
+use crate::fileinfo::FileInfo;
+
+/// File in the synced directory listing the syncs whose backups can be
+/// restored, oldest first.
+pub const JOURNAL: &str = ".dirsync-backup.json";
+
+/// A change a sync made.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the synced directory.
+    pub path: String,
+    /// Where the entry went: its backup, or for `moved` files their new place
+    /// in the synced directory. None for `added` entries.
+    pub moved_to: Option<PathBuf>,
+    /// `added`, `replaced`, `deleted` or `moved`.
+    pub kind: String,
+    /// What the sync left at `path`, or at `moved_to` for `moved` files, as
+    /// returned by `fingerprint`. None for `deleted` entries.
+    pub written: Option<String>,
+}
+
+/// What one sync changed, enough to undo it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Session {
+    /// Local time of the sync, in RFC 3339.
+    pub started_at: String,
+    /// Directory holding this sync's backups, when backups go to a directory.
+    pub dir: Option<PathBuf>,
+    /// In the order they were made.
+    pub entries: Vec<Entry>,
+    pub created_dirs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    pub sessions: Vec<Session>,
+}
+
+impl Journal {
+    /// Loads the journal of the synced directory `root`, empty without one.
+    pub fn load(root: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(root.join(JOURNAL)) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self, root: &Path) -> std::io::Result<()> {
+        let path = root.join(JOURNAL);
+        if self.sessions.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        let tmp_path = tmp_path(&path);
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(self).unwrap())?;
+        std::fs::rename(&tmp_path, &path)
+    }
+
+    /// The backups of all syncs, which a sync leaves alone.
+    pub fn backups(&self) -> impl Iterator<Item = &Path> {
+        self.sessions
+            .iter()
+            .flat_map(|session| &session.entries)
+            .filter(|entry| entry.kind == "replaced" || entry.kind == "deleted")
+            .filter_map(|entry| entry.moved_to.as_deref())
+    }
+}
+
+/// What is at `path`, to tell whether it is still what a sync left there:
+/// the content hash of a file, `link:` and the target of a link, empty for
+/// a directory, None for nothing.
+pub fn fingerprint(path: &Path) -> std::io::Result<Option<String>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => Ok(Some(format!(
+            "link:{}",
+            std::fs::read_link(path)?.to_string_lossy()
+        ))),
+        Ok(meta) if meta.is_dir() => Ok(Some(String::new())),
+        Ok(_) => Ok(Some(FileInfo::new(&path.to_path_buf())?.hash)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Keeps the entries a sync replaces or deletes in a backup, and records
+/// what the sync changed in the journal. Shared by the download workers.
+///
+/// Backups go to `<dir>/<time>/<path><suffix>` with a backup directory,
+/// otherwise next to the entry as `<path>.<time><suffix>`.
+pub struct Backup {
+    root: PathBuf,
+    suffix: String,
+    stamp: String,
+    journal: Journal,
+    session: Mutex<Session>,
+    /// The session changed since it was last written.
+    dirty: AtomicBool,
+}
+
+impl Backup {
+    pub fn new(root: &Path, dir: Option<&Path>, suffix: &str, journal: Journal) -> Self {
+        let now = Local::now();
+        let stamp = now.format("%Y%m%d-%H%M%S").to_string();
+        let dir =
+            dir.map(|dir| unique(std::path::absolute(dir).unwrap_or(dir.into()).join(&stamp)));
+        Self {
+            root: root.to_path_buf(),
+            suffix: suffix.to_string(),
+            stamp,
+            journal,
+            session: Mutex::new(Session {
+                started_at: now.to_rfc3339(),
+                dir,
+                ..Default::default()
+            }),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn record(&self, path: &Path, moved_to: Option<PathBuf>, kind: &str, written: Option<String>) {
+        self.session.lock().unwrap().entries.push(Entry {
+            path: self.relative(path),
+            moved_to,
+            kind: kind.to_string(),
+            written,
+        });
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// A free path for the backup of the entry at `path`.
+    fn backup_path(&self, path: &Path) -> PathBuf {
+        let mut backup = match &self.session.lock().unwrap().dir {
+            Some(dir) => dir.join(self.relative(path)).into_os_string(),
+            None => {
+                let mut backup = path.as_os_str().to_os_string();
+                backup.push(format!(".{}", self.stamp));
+                backup
+            }
+        };
+        backup.push(&self.suffix);
+        unique(PathBuf::from(backup))
+    }
+
+    /// Moves the entry at `path`, a file, link or directory, to its backup.
+    pub fn delete(&self, path: &Path) -> std::io::Result<()> {
+        let backup = self.backup_path(path);
+        move_entry(path, &backup)?;
+        debug!(path = %path.display(), backup = %backup.display(), "backed up");
+        self.record(path, Some(backup), "deleted", None);
+        Ok(())
+    }
+
+    /// Runs `replace`, which puts a new file or link at `path`, keeping the
+    /// entry there before in a backup. A directory is moved to its backup
+    /// first, to make room. `written` is the content hash of the new file,
+    /// for links it is read afterwards. The change is only recorded once
+    /// `replace` succeeded, otherwise the backup is dropped, or the directory
+    /// moved back, and the entry at `path` is left as it was.
+    pub fn replace<T>(
+        &self,
+        path: &Path,
+        written: Option<&str>,
+        replace: impl FnOnce() -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let (kept, is_dir) = match std::fs::symlink_metadata(path) {
+            Ok(meta) => {
+                let backup = self.backup_path(path);
+                if meta.is_dir() {
+                    move_entry(path, &backup)?;
+                } else {
+                    copy_entry(path, &backup)?;
+                }
+                (Some(backup), meta.is_dir())
+            }
+            Err(_) => (None, false),
+        };
+        let value = match replace() {
+            Ok(value) => value,
+            Err(err) => {
+                match &kept {
+                    Some(backup) if is_dir => {
+                        let _ = move_entry(backup, path);
+                    }
+                    Some(backup) => {
+                        let _ = std::fs::remove_file(backup);
+                    }
+                    None => {}
+                }
+                return Err(err);
+            }
+        };
+        let written = match written {
+            Some(written) => Some(written.to_string()),
+            None => fingerprint(path).ok().flatten(),
+        };
+        match kept {
+            Some(backup) => {
+                debug!(path = %path.display(), backup = %backup.display(), "backed up");
+                self.record(path, Some(backup), "replaced", written);
+            }
+            None => self.record(path, None, "added", written),
+        }
+        Ok(value)
+    }
+
+    /// Records that the file at `from` with content `hash` was moved to `to`.
+    pub fn moved(&self, from: &Path, to: &Path, hash: &str) {
+        self.record(
+            from,
+            Some(to.to_path_buf()),
+            "moved",
+            Some(hash.to_string()),
+        );
+    }
+
+    pub fn created_dir(&self, path: &Path) {
+        let path = self.relative(path);
+        self.session.lock().unwrap().created_dirs.push(path);
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Writes the journal with this sync in it, unless it changed nothing
+    /// since the last time.
+    pub fn commit(&self) -> std::io::Result<()> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut journal = self.journal.clone();
+        journal.sessions.push(self.session.lock().unwrap().clone());
+        let result = journal.save(&self.root);
+        if result.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+/// A sync stopped by an error still journals what it changed until then.
+impl Drop for Backup {
+    fn drop(&mut self) {
+        if let Err(err) = self.commit() {
+            warn!("backup journal not written: {}", err);
+        }
+    }
+}
+
+/// `path`, or `path.1`, `path.2`... if something is there already.
+fn unique(path: PathBuf) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut n = 0;
+    while std::fs::symlink_metadata(&candidate).is_ok() {
+        n += 1;
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        candidate = PathBuf::from(name);
+    }
+    candidate
+}
+
+fn create_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Moves the entry at `from` to `to`, creating the directories above `to`.
+/// Files are copied and removed where they can't be renamed, like across
+/// file systems.
+fn move_entry(from: &Path, to: &Path) -> std::io::Result<()> {
+    create_parent(to)?;
+    match std::fs::rename(from, to) {
+        Err(_) if std::fs::symlink_metadata(from)?.is_file() => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Keeps the file or link at `from` at `to` as well, as a hardlink where
+/// possible. Replacing `from` puts a new file in its place, the hardlink keeps
+/// the old one. Files with other links are copied, changes made through
+/// those would reach the backup.
+fn copy_entry(from: &Path, to: &Path) -> std::io::Result<()> {
+    create_parent(to)?;
+    let meta = std::fs::symlink_metadata(from)?;
+    #[cfg(unix)]
+    let single_link = std::os::unix::fs::MetadataExt::nlink(&meta) == 1;
+    #[cfg(not(unix))]
+    let single_link = false;
+    if (single_link || meta.file_type().is_symlink()) && std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    if meta.file_type().is_symlink() {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(std::fs::read_link(from)?, to);
+    }
+    std::fs::copy(from, to).map(|_| ())
+}
+
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Removes `dir` and the directories below it if they hold nothing else.
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+    }
+    let _ = std::fs::remove_dir(dir);
+}
+
+/// Undoes `entry` of a sync of `root`. Returns false, changing nothing, if
+/// what the sync left was changed since.
+fn undo(root: &Path, entry: &Entry) -> std::io::Result<bool> {
+    let path = root.join(&entry.path);
+    let Some(moved_to) = &entry.moved_to else {
+        // added
+        let now = fingerprint(&path)?;
+        if now.is_some() && now != entry.written {
+            return Ok(false);
+        }
+        remove_entry(&path)?;
+        return Ok(true);
+    };
+    if std::fs::symlink_metadata(moved_to).is_err() {
+        warn!(path = %entry.path, "{} is gone", moved_to.display());
+        return Ok(true);
+    }
+    let changed = if entry.kind == "moved" {
+        fingerprint(moved_to)? != entry.written || fingerprint(&path)?.is_some()
+    } else {
+        fingerprint(&path)?.is_some_and(|now| Some(now) != entry.written)
+    };
+    if changed {
+        return Ok(false);
+    }
+    remove_entry(&path)?;
+    move_entry(moved_to, &path)?;
+    Ok(true)
+}
+
+/// What `restore` did.
+#[derive(Debug)]
+pub struct Restored {
+    /// Local time of the undone sync, in RFC 3339.
+    pub started_at: String,
+    /// Changes undone.
+    pub undone: usize,
+    /// Paths changed since the sync, which were left alone. Their changes
+    /// stay in the journal for a later restore.
+    pub conflicts: Vec<String>,
+}
+
+/// Undoes the last sync of the synced directory `root` which changed
+/// something: puts back what it replaced, deleted or moved, and removes what
+/// it added. What was changed since the sync is left alone. Returns `None`
+/// if there is no sync to undo.
+pub fn restore(root: &Path) -> std::io::Result<Option<Restored>> {
+    let mut journal = Journal::load(root)?;
+    let Some(mut session) = journal.sessions.pop() else {
+        return Ok(None);
+    };
+    let mut restored = Restored {
+        started_at: session.started_at.clone(),
+        undone: 0,
+        conflicts: Vec::new(),
+    };
+    let mut left = Vec::new();
+    while let Some(entry) = session.entries.pop() {
+        match undo(root, &entry) {
+            Ok(true) => {
+                debug!(path = %entry.path, kind = %entry.kind, "restored");
+                restored.undone += 1;
+            }
+            Ok(false) => {
+                warn!(path = %entry.path, kind = %entry.kind, "changed since the sync, left alone");
+                restored.conflicts.push(entry.path.clone());
+                left.push(entry);
+            }
+            Err(err) => {
+                // what is left to undo stays in the journal
+                session.entries.push(entry);
+                session.entries.extend(left.into_iter().rev());
+                journal.sessions.push(session);
+                journal.save(root)?;
+                return Err(err);
+            }
+        }
+    }
+    for dir in session.created_dirs.drain(..).rev() {
+        let _ = std::fs::remove_dir(root.join(dir));
+    }
+    if !left.is_empty() {
+        session.entries = left.into_iter().rev().collect();
+        journal.sessions.push(session);
+    } else if let Some(dir) = &session.dir {
+        remove_empty_dirs(dir);
+    }
+    journal.save(root)?;
+    Ok(Some(restored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileinfo::get_hash;
+
+    /// Writes `path` like a sync does, renaming a new file over it.
+    fn put(path: &Path, content: &str) -> std::io::Result<()> {
+        let tmp_path = tmp_path(path);
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    #[test]
+    fn test_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("old")).unwrap();
+        std::fs::write(root.join("a.txt"), "before").unwrap();
+        std::fs::write(root.join("old/b.txt"), "deleted").unwrap();
+        std::fs::write(root.join("c.txt"), "moved").unwrap();
+        std::fs::create_dir_all(root.join("e")).unwrap();
+        std::fs::write(root.join("e/f.txt"), "in a dir").unwrap();
+
+        // a sync deleting old, moving c.txt, replacing a.txt, adding d.txt and
+        // replacing the directory e with a file
+        let backup = Backup::new(root, None, "~", Journal::default());
+        backup.delete(&root.join("old")).unwrap();
+        std::fs::create_dir(root.join("new")).unwrap();
+        backup.created_dir(&root.join("new"));
+        std::fs::rename(root.join("c.txt"), root.join("new/c.txt")).unwrap();
+        backup.moved(
+            &root.join("c.txt"),
+            &root.join("new/c.txt"),
+            &get_hash(b"moved"),
+        );
+        let after = get_hash(b"after");
+        let a = root.join("a.txt");
+        backup
+            .replace(&a, Some(&after), || put(&a, "after"))
+            .unwrap();
+        let d = root.join("d.txt");
+        backup
+            .replace(&d, Some(&get_hash(b"added")), || put(&d, "added"))
+            .unwrap();
+        let e = root.join("e");
+        backup
+            .replace(&e, Some(&get_hash(b"file")), || put(&e, "file"))
+            .unwrap();
+        // a failed replacement changes nothing
+        let failed = backup.replace(&a, Some(&after), || -> std::io::Result<()> {
+            Err(std::io::ErrorKind::Other.into())
+        });
+        assert!(failed.is_err());
+        let failed = backup.replace(&e, Some(&after), || -> std::io::Result<()> {
+            Err(std::io::ErrorKind::Other.into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read(&e).unwrap(), b"file");
+        std::fs::create_dir(root.join("g")).unwrap();
+        let failed = backup.replace(&root.join("g"), Some(&after), || -> std::io::Result<()> {
+            Err(std::io::ErrorKind::Other.into())
+        });
+        assert!(failed.is_err());
+        assert!(root.join("g").is_dir());
+        backup.commit().unwrap();
+        drop(backup);
+
+        let journal = Journal::load(root).unwrap();
+        assert_eq!(journal.sessions.len(), 1);
+        assert_eq!(journal.sessions[0].entries.len(), 5);
+        assert_eq!(journal.sessions[0].entries[4].kind, "replaced");
+        assert_eq!(journal.backups().count(), 3);
+        assert!(!root.join("old").exists());
+
+        let restored = restore(root).unwrap().unwrap();
+        assert_eq!(restored.undone, 5);
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"before");
+        assert_eq!(std::fs::read(root.join("old/b.txt")).unwrap(), b"deleted");
+        assert_eq!(std::fs::read(root.join("c.txt")).unwrap(), b"moved");
+        assert_eq!(std::fs::read(root.join("e/f.txt")).unwrap(), b"in a dir");
+        assert!(!root.join("d.txt").exists());
+        assert!(!root.join("new").exists());
+        assert!(!root.join(JOURNAL).exists());
+        assert_eq!(std::fs::read_dir(root).unwrap().count(), 5);
+        assert!(restore(root).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restore_keeps_later_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::write(root.join("a.txt"), "before").unwrap();
+
+        let backup = Backup::new(root, None, "~", Journal::default());
+        let (a, d) = (root.join("a.txt"), root.join("d.txt"));
+        backup
+            .replace(&a, Some(&get_hash(b"after")), || put(&a, "after"))
+            .unwrap();
+        backup
+            .replace(&d, Some(&get_hash(b"added")), || put(&d, "added"))
+            .unwrap();
+        drop(backup);
+        // edited after the sync
+        std::fs::write(&a, "edited").unwrap();
+        std::fs::write(&d, "edited").unwrap();
+
+        let restored = restore(root).unwrap().unwrap();
+        assert_eq!(restored.undone, 0);
+        assert_eq!(restored.conflicts, vec!["d.txt", "a.txt"]);
+        assert_eq!(std::fs::read(&a).unwrap(), b"edited");
+        assert_eq!(std::fs::read(&d).unwrap(), b"edited");
+        // the backup is still journaled, and restored once the edit is gone
+        assert_eq!(Journal::load(root).unwrap().backups().count(), 1);
+        std::fs::remove_file(&a).unwrap();
+        let restored = restore(root).unwrap().unwrap();
+        assert_eq!(restored.undone, 1);
+        assert_eq!(std::fs::read(&a).unwrap(), b"before");
+    }
+}
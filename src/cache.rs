@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// A slot in the cache. The slot mutex is held while its value is being loaded,
+/// so concurrent requests for the same key wait for the first loader instead of
+/// loading the value again.
+#[derive(Default)]
+struct Entry {
+    value: Mutex<Option<Arc<Vec<u8>>>>,
+}
+
+/// Cache of compressed file contents with per-entry single-flight loading.
+///
+/// The map lock is only held to look up or insert a slot, never while loading,
+/// so loading one file doesn't block requests for other files.
+#[derive(Default)]
+pub struct FileCache {
+    entries: Mutex<HashMap<String, Arc<Entry>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Counters and size of a `FileCache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of loaded entries.
+    pub entries: usize,
+    /// Total size of the loaded entries.
+    pub bytes: u64,
+}
+
+impl FileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entry(&self, key: &str) -> Arc<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(key.to_string()).or_default().clone()
+    }
+
+    /// Returns the cached value for `key`, calling `load` to fill it if needed.
+    /// A failed load is not cached and its slot is dropped, the next caller
+    /// will try again. A loader which panicked left the slot empty, so the
+    /// poisoned lock is taken over.
+    pub fn get_or_load<E>(
+        &self,
+        key: &str,
+        load: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Arc<Vec<u8>>, E> {
+        let entry = self.entry(key);
+        let mut value = entry.value.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(buf) = value.as_ref() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(buf.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        match load() {
+            Ok(buf) => {
+                let buf = Arc::new(buf);
+                *value = Some(buf.clone());
+                Ok(buf)
+            }
+            Err(err) => {
+                let mut entries = self.entries.lock().unwrap();
+                if entries
+                    .get(key)
+                    .is_some_and(|slot| Arc::ptr_eq(slot, &entry))
+                {
+                    entries.remove(key);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Drops all entries. Loads already in flight finish into their detached
+    /// slots and are not visible to later requests.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Drops the entries whose key `keep` rejects.
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) {
+        self.entries.lock().unwrap().retain(|key, _| keep(key));
+    }
+
+    /// Entries being loaded right now are not counted.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for entry in self.entries.lock().unwrap().values() {
+            if let Ok(value) = entry.value.try_lock() {
+                if let Some(buf) = value.as_ref() {
+                    stats.entries += 1;
+                    stats.bytes += buf.len() as u64;
+                }
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_single_flight() {
+        let cache = Arc::new(FileCache::new());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                let loads = loads.clone();
+                std::thread::spawn(move || {
+                    cache
+                        .get_or_load("a", || -> Result<Vec<u8>, ()> {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(50));
+                            Ok(vec![1, 2, 3])
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(*handle.join().unwrap(), vec![1, 2, 3]);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_failed_load_is_retried() {
+        let cache = FileCache::new();
+        assert!(cache.get_or_load("a", || Err(())).is_err());
+        assert!(cache.entries.lock().unwrap().is_empty());
+        let buf = cache.get_or_load("a", || -> Result<_, ()> { Ok(vec![7]) });
+        assert_eq!(*buf.unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn test_panicked_load_is_retried() {
+        let cache = FileCache::new();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cache.get_or_load("a", || -> Result<Vec<u8>, ()> { panic!("load failed") })
+        }));
+        assert!(panicked.is_err());
+        let buf = cache.get_or_load("a", || -> Result<_, ()> { Ok(vec![7]) });
+        assert_eq!(*buf.unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = FileCache::new();
+        let load = || -> Result<_, ()> { Ok(vec![0; 10]) };
+        cache.get_or_load("a", load).unwrap();
+        cache.get_or_load("a", load).unwrap();
+        cache.get_or_load("b", load).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!((stats.entries, stats.bytes), (2, 20));
+        cache.retain(|key| key == "b");
+        assert_eq!(cache.stats().entries, 1);
+        cache.clear();
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -1,14 +1,177 @@
-use core::panic;
+use std::collections::{HashMap, HashSet};
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
+use chrono::{DateTime, FixedOffset};
+use tracing::{debug, error, info, warn};
+
+use crate::backup::{self, Backup, Journal};
 use crate::common::*;
 use crate::fileinfo::*;
+use crate::history::{version_at, VersionInfo};
+use crate::names::NamePolicy;
+use crate::progress::{Metered, Progress};
+use crate::ratelimit::{Throttled, TokenBucket};
+use crate::release;
+use crate::report::{
+    FailedEntry, FileEntry, LinkEntry, MoveEntry, ReportFormat, ReuseEntry, SyncReport,
+};
+use crate::state::{self, State};
+
+/// An authenticated connection to the server.
+type Connection = Throttled<Metered<TcpStream>>;
+
+/// How a file is created from a local file with the same content, instead of
+/// being downloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReusePolicy {
+    #[default]
+    Copy,
+    /// Link the files, which then share later changes to either one.
+    Hardlink,
+    /// Download every file.
+    Off,
+}
+
+impl std::str::FromStr for ReusePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(ReusePolicy::Copy),
+            "hardlink" => Ok(ReusePolicy::Hardlink),
+            "off" => Ok(ReusePolicy::Off),
+            _ => Err(format!("unknown reuse policy: {}", s)),
+        }
+    }
+}
+
+/// What a sync does with files changed locally since the last sync, whose
+/// content differs from the server's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LocalChangePolicy {
+    /// Replace them with the server's content like any other file.
+    #[default]
+    Overwrite,
+    /// Keep them, they are updated again once they have the content of the
+    /// last sync or the server's.
+    Skip,
+    /// Fail the sync before changing anything.
+    Abort,
+}
+
+impl std::str::FromStr for LocalChangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(LocalChangePolicy::Overwrite),
+            "skip" => Ok(LocalChangePolicy::Skip),
+            "abort" => Ok(LocalChangePolicy::Abort),
+            _ => Err(format!("unknown local change policy: {}", s)),
+        }
+    }
+}
+
+/// Settings of `client_main`.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub server: String,
+    pub dir: String,
+    pub auth_key: String,
+    pub dry_run: bool,
+    /// Number of connections used to download files.
+    pub jobs: usize,
+    /// Download bytes per second over all connections, 0 for unlimited.
+    pub bwlimit: u64,
+    /// Remove local files and directories which are not on the server.
+    pub delete: bool,
+    /// What to do with server names which can't be created locally.
+    pub names: NamePolicy,
+    /// Replace this binary with the server's release for this platform.
+    pub self_update: bool,
+    /// Public key releases must be signed with, in hex or as a file holding the hex.
+    pub update_key: Option<String>,
+    /// Release version to update to, even an older one. The latest release
+    /// when not set.
+    pub update_version: Option<String>,
+    /// Format of the sync report, which is only written when set or when
+    /// `report_file` is.
+    pub report: Option<ReportFormat>,
+    /// File the report is written to instead of stdout.
+    pub report_file: Option<PathBuf>,
+    /// Show the download progress on stdout.
+    pub progress: bool,
+    /// How files whose content is already in the local directory are created.
+    pub reuse: ReusePolicy,
+    /// What to do with files changed locally since the last sync.
+    pub local_changes: LocalChangePolicy,
+    /// Version of the server's history to sync instead of its current files.
+    pub version: Option<u64>,
+    /// Sync the version of the server's history current at this time.
+    pub at: Option<DateTime<FixedOffset>>,
+    /// Directory the files a sync replaces or deletes are moved to, below a
+    /// directory named after the time of the sync.
+    pub backup_dir: Option<PathBuf>,
+    /// Appended to the names of backups. Without `backup_dir`, backups are
+    /// kept next to the files with the time of the sync and this suffix.
+    pub backup_suffix: Option<String>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            server: "127.0.0.1:9022".to_string(),
+            dir: ".".to_string(),
+            auth_key: "friday".to_string(),
+            dry_run: false,
+            jobs: 1,
+            bwlimit: 0,
+            delete: false,
+            names: NamePolicy::default(),
+            self_update: false,
+            update_key: None,
+            update_version: None,
+            report: None,
+            report_file: None,
+            progress: true,
+            reuse: ReusePolicy::default(),
+            local_changes: LocalChangePolicy::default(),
+            version: None,
+            at: None,
+            backup_dir: None,
+            backup_suffix: None,
+        }
+    }
+}
+
+impl ClientOptions {
+    fn report_format(&self) -> Option<ReportFormat> {
+        self.report
+            .or(self.report_file.as_ref().map(|_| ReportFormat::Json))
+    }
+
+    /// The dry-run plan and summary lines go to stdout, unless the report does.
+    fn prints_plan(&self) -> bool {
+        self.report_format().is_none() || self.report_file.is_some()
+    }
+
+    fn shows_progress(&self) -> bool {
+        self.progress && !self.dry_run && self.prints_plan()
+    }
+
+    fn backs_up(&self) -> bool {
+        self.backup_dir.is_some() || self.backup_suffix.is_some()
+    }
+}
 
 fn do_request(
     request: &Request,
-    client: &mut TcpStream,
+    client: &mut Connection,
 ) -> Result<Response, Box<dyn std::error::Error>> {
     let frame = Frame::from_request(&request);
     frame.write(client)?;
@@ -17,141 +180,1540 @@ fn do_request(
     Ok(response)
 }
 
-pub fn client_main(
+/// The name this client reports to the server, its host name if known.
+fn client_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Opens a connection to the server and authenticates it. Bytes read from it
+/// are added to `received`.
+fn connect(
     server: &str,
-    dir: &str,
     auth_key: &str,
-    dry_run: bool,
-    verbose: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if dry_run {
-        println!("dry run");
+    bwlimit: &Arc<TokenBucket>,
+    received: &Arc<AtomicU64>,
+) -> Result<Connection, Box<dyn std::error::Error>> {
+    let client = TcpStream::connect(server)?;
+    client.set_nodelay(true)?;
+    let client = Metered::new(client, received.clone());
+    let mut client = Throttled::new(client, vec![bwlimit.clone()]);
+    let request = Request::Auth(auth_key.to_string(), client_name());
+    match do_request(&request, &mut client)? {
+        Response::Auth(true) => Ok(client),
+        Response::Error(err) => Err(err.into()),
+        _ => Err("auth failed".into()),
     }
-    if verbose {
-        println!("sync {} from {}", dir, server);
+}
+
+/// Sends an admin request, authenticated with the server's admin key, and
+/// returns the response.
+pub fn admin(
+    server: &str,
+    admin_key: &str,
+    request: &Request,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let bwlimit = Arc::new(TokenBucket::new(0));
+    let mut client = connect(server, admin_key, &bwlimit, &Arc::default())?;
+    do_request(request, &mut client)
+}
+
+/// Lists the client releases the server offers.
+pub fn list_releases(
+    server: &str,
+    auth_key: &str,
+) -> Result<Vec<release::Release>, Box<dyn std::error::Error>> {
+    let bwlimit = Arc::new(TokenBucket::new(0));
+    let mut client = connect(server, auth_key, &bwlimit, &Arc::default())?;
+    match do_request(&Request::ListReleases, &mut client)? {
+        Response::Releases(releases) => Ok(releases),
+        Response::Error(err) => Err(err.into()),
+        _ => Err("unexpected response".into()),
     }
-    let total_clock = Instant::now();
+}
 
-    let mut client = TcpStream::connect(server)?;
-    //auth request
-    let request = Request::Auth(auth_key.to_string());
-    let response = do_request(&request, &mut client)?;
-    match response {
+pub fn list_versions(
+    server: &str,
+    auth_key: &str,
+) -> Result<Vec<VersionInfo>, Box<dyn std::error::Error>> {
+    let bwlimit = Arc::new(TokenBucket::new(0));
+    let mut client = connect(server, auth_key, &bwlimit, &Arc::default())?;
+    request_versions(&mut client)
+}
+
+fn request_versions(
+    client: &mut Connection,
+) -> Result<Vec<VersionInfo>, Box<dyn std::error::Error>> {
+    match do_request(&Request::ListVersions, client)? {
+        Response::Versions(versions) => Ok(versions),
+        Response::Error(err) => Err(err.into()),
+        _ => Err("unexpected response".into()),
+    }
+}
+
+/// The request for the file list to sync: a version of the server's history
+/// when one is asked for, else the current files.
+fn dir_info_request(
+    options: &ClientOptions,
+    client: &mut Connection,
+    report: &mut SyncReport,
+) -> Result<Request, Box<dyn std::error::Error>> {
+    let version = match (options.version, options.at) {
+        (Some(version), _) => version,
+        (None, Some(at)) => {
+            let versions = request_versions(client)?;
+            let version = version_at(&versions, at)
+                .ok_or_else(|| format!("the server's history has no version at {}", at))?;
+            info!(
+                version = version.version,
+                created_at = %version.created_at,
+                "version at {}", at
+            );
+            version.version
+        }
+        (None, None) => return Ok(Request::GetDirInfo("".to_string())),
+    };
+    report.version = Some(version);
+    Ok(Request::GetVersion(version))
+}
+
+/// Updates this binary to the server's signed release for this platform and
+/// runs the sync again with the new binary, returning its exit status. Returns
+/// `None` when already up to date. If the new binary fails to start, the old
+/// one is put back.
+fn self_update(
+    client: &mut Connection,
+    options: &ClientOptions,
+) -> Result<Option<std::process::ExitStatus>, Box<dyn std::error::Error>> {
+    let key = release::parse_public_key(
+        options
+            .update_key
+            .as_deref()
+            .ok_or("--self-update requires --update-key")?,
+    )?;
+    let target = release::current_target();
+    let request = Request::GetRelease(target.clone(), options.update_version.clone());
+    let release = match do_request(&request, client)? {
+        Response::Release(release) => release,
         Response::Error(err) => {
-            println!("{}", err);
-            return Ok(());
+            info!(%target, "no update: {}", err);
+            return Ok(None);
+        }
+        _ => return Err("unexpected response".into()),
+    };
+    let exe_path = std::env::current_exe()?;
+    if FileInfo::new(&exe_path)?.hash == release.hash {
+        return Ok(None);
+    }
+    // only a pinned version may take this binary back to an older release
+    if options.update_version.is_none()
+        && release::compare_versions(&release.version, release::VERSION).is_lt()
+    {
+        debug!(version = %release.version, "not updating to an older release");
+        return Ok(None);
+    }
+    let request = Request::GetReleaseFile(target, release.version.clone());
+    let content = match do_request(&request, client)? {
+        Response::File(content) => decompress_bytes(&content)?,
+        Response::Error(err) => return Err(err.into()),
+        _ => return Err("unexpected response".into()),
+    };
+    release.verify(&key, &content)?;
+    if options.dry_run {
+        if options.prints_plan() {
+            println!("update self: {} -> {}", release::VERSION, release.version);
+        }
+        return Ok(None);
+    }
+    info!(from = release::VERSION, to = %release.version, "updating self");
+    let backup = release::install(&exe_path, &content)?;
+    let status = release::check_binary(&exe_path, &release.version).and_then(|_| {
+        Command::new(&exe_path)
+            .args(std::env::args_os().skip(1))
+            .env(release::UPDATED_ENV, "1")
+            .status()
+            .map_err(|err| format!("new binary failed to start: {}", err))
+    });
+    match status {
+        Ok(status) => Ok(Some(status)),
+        Err(err) => {
+            release::rollback(&exe_path, &backup)?;
+            Err(format!("{}, rolled back to {}", err, release::VERSION).into())
         }
-        _ => {}
     }
+}
 
-    //check self update
-    let request = Request::GetFileHash("self".to_string());
-    let response = do_request(&request, &mut client)?;
+/// Files up to this size are requested in batches with `Request::GetBlobs`.
+const BATCH_FILE_SIZE: u64 = 64 * 1024;
+/// Limits of a single batch, by total file size and by number of files.
+const BATCH_MAX_BYTES: u64 = 1024 * 1024;
+const BATCH_MAX_FILES: usize = 256;
+
+/// Groups the indices of `files` into download batches. Large files get a
+/// batch of their own, small files are packed together up to the batch limits.
+fn plan_batches(files: &[&FileInfo]) -> Vec<Vec<usize>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    for (index, file_info) in files.iter().enumerate() {
+        if file_info.size > BATCH_FILE_SIZE {
+            batches.push(vec![index]);
+            continue;
+        }
+        if batch.len() == BATCH_MAX_FILES || batch_bytes + file_info.size > BATCH_MAX_BYTES {
+            batches.push(std::mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+        batch.push(index);
+        batch_bytes += file_info.size;
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Runs `write`, which puts a new file or link at `path`, keeping what was
+/// there in `backup` when the sync keeps backups. `written` is the content
+/// hash of the new file.
+fn replace_entry<T>(
+    backup: Option<&Backup>,
+    path: &Path,
+    written: Option<&str>,
+    write: impl FnOnce() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    match backup {
+        Some(backup) => backup.replace(path, written, write),
+        None => write(),
+    }
+}
+
+fn save_file(
+    response: Response,
+    file_info: &FileInfo,
+    download_clock: Instant,
+    progress: &Progress,
+    backup: Option<&Backup>,
+) -> Result<(), Box<dyn std::error::Error>> {
     match response {
-        Response::FileHash(self_hash) => {
-            let exe_path = std::env::current_exe()?;
-            let exe_info = FileInfo::new(&exe_path)?;
-            if self_hash != exe_info.hash {
-                let request = Request::GetFile("self".to_string());
-                let response = do_request(&request, &mut client)?;
-                match response {
-                    Response::File(content) => {
-                        if verbose {
-                            println!("updating self");
-                        }
-                        //move old self to .bak
-                        let current_time =
-                            SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-                        let self_name = exe_path.file_name().unwrap().to_str().unwrap().to_string();
-                        let mut bak_path = exe_path.clone();
-                        bak_path.set_file_name(format!("{}.{}.bak", self_name, current_time));
-                        std::fs::rename(&exe_path, &bak_path)?;
-                        write_compressed_file(exe_path.as_path(), content.as_slice())?;
-                        std::thread::sleep(Duration::from_secs(1));
-
-                        let mut cmd = Command::new(exe_path);
-                        cmd.arg("sync").arg("-s").arg(server).arg("-d").arg(dir);
-                        if verbose {
-                            cmd.arg("-v");
-                        }
-                        if dry_run {
-                            cmd.arg("--dry-run");
-                        }
-                        let output = cmd.output()?;
-                        println!("output of new process");
-                        println!("{}", String::from_utf8_lossy(&output.stdout));
-                        println!("{}", String::from_utf8_lossy(&output.stderr));
-                        return Ok(());
+        Response::File(content) => {
+            progress.file_done(file_info.size, content.len() as u64);
+            replace_entry(backup, &file_info.path, Some(&file_info.hash), || {
+                write_compressed_file(&file_info.path, content.as_slice(), &file_info.hash)
+            })?;
+            debug!(
+                path = %file_info.path.display(),
+                size = file_info.size,
+                elapsed = %human_duration(download_clock.elapsed()),
+                "downloaded"
+            );
+            Ok(())
+        }
+        Response::Error(err) => Err(err.into()),
+        _ => Err("unexpected response".into()),
+    }
+}
+
+/// Downloads one batch of files by content hash. A single file is fetched
+/// with `GetBlob`, several with one `GetBlobs` request whose responses arrive
+/// back-to-back.
+/// Returns the time taken or the error message of every file in the batch.
+fn download_batch(
+    client: &mut Connection,
+    files: &[&FileInfo],
+    batch: &[usize],
+    progress: &Progress,
+    backup: Option<&Backup>,
+) -> Vec<(usize, Result<Duration, String>)> {
+    let download_clock = Instant::now();
+    let start_file = |file_info: &FileInfo| {
+        let name = file_info.path.file_name().unwrap_or_default();
+        progress.start_file(&name.to_string_lossy());
+    };
+    if let [index] = batch {
+        let file_info = files[*index];
+        start_file(file_info);
+        let request = Request::GetBlob(file_info.hash.clone());
+        let result = do_request(&request, client)
+            .and_then(|response| save_file(response, file_info, download_clock, progress, backup));
+        return vec![(*index, done(result, download_clock))];
+    }
+
+    let hashes = batch.iter().map(|i| files[*i].hash.clone()).collect();
+    let request = Request::GetBlobs(hashes);
+    if let Err(err) = Frame::from_request(&request).write(client) {
+        return batch.iter().map(|i| (*i, Err(err.to_string()))).collect();
+    }
+    let mut results = Vec::new();
+    for (pos, index) in batch.iter().enumerate() {
+        start_file(files[*index]);
+        let response = match Frame::read(client) {
+            Ok(frame) => frame.to_response(),
+            Err(err) => {
+                // the connection is broken, the rest of the batch is lost
+                results.extend(batch[pos..].iter().map(|i| (*i, Err(err.to_string()))));
+                break;
+            }
+        };
+        let result = match response {
+            Ok(response) => save_file(response, files[*index], download_clock, progress, backup),
+            Err(err) => Err(format!("{:?}", err).into()),
+        };
+        results.push((*index, done(result, download_clock)));
+    }
+    results
+}
+
+fn done(
+    result: Result<(), Box<dyn std::error::Error>>,
+    clock: Instant,
+) -> Result<Duration, String> {
+    result
+        .map(|_| clock.elapsed())
+        .map_err(|err| err.to_string())
+}
+
+/// Downloads the `batches` of `files` over the given connections, one worker
+/// thread per connection. Returns the time taken or the error message of
+/// every file, in the order of `files`.
+fn download_files(
+    clients: Vec<Connection>,
+    files: &[&FileInfo],
+    batches: &[Vec<usize>],
+    progress: &Progress,
+    backup: Option<&Backup>,
+) -> Vec<(usize, Result<Duration, String>)> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = clients
+            .into_iter()
+            .map(|mut client| {
+                let next = &next;
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(batch) = batches.get(index) else {
+                            break;
+                        };
+                        results.extend(download_batch(&mut client, files, batch, progress, backup));
                     }
-                    _ => {}
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results
+}
+
+/// Creates `file_info.path` from the local file `source`, which had the same
+/// content when the directory was compared. Hardlinks fall back to copies
+/// where they can't be created, like across file systems. Returns `hardlink`
+/// or `copy`, whichever was made.
+fn reuse_file(
+    source: &Path,
+    file_info: &FileInfo,
+    reuse: ReusePolicy,
+) -> std::io::Result<&'static str> {
+    if reuse == ReusePolicy::Hardlink
+        && FileInfo::new(&source.to_path_buf())?.hash == file_info.hash
+    {
+        let tmp_path = tmp_path(&file_info.path);
+        let _ = std::fs::remove_file(&tmp_path);
+        match std::fs::hard_link(source, &tmp_path) {
+            Ok(()) => {
+                let result = std::fs::rename(&tmp_path, &file_info.path);
+                if result.is_err() {
+                    let _ = std::fs::remove_file(&tmp_path);
                 }
+                return result.map(|_| "hardlink");
+            }
+            Err(err) => debug!(source = %source.display(), "hardlink failed, copying: {}", err),
+        }
+    }
+    write_file(&file_info.path, &std::fs::read(source)?, &file_info.hash)?;
+    Ok("copy")
+}
+
+/// Moves the local file `source` to `file_info.path`, which is to have the
+/// content `source` had when the directory was compared. Files which can't
+/// be renamed, like across file systems, are copied and removed.
+fn move_file(source: &Path, file_info: &FileInfo) -> std::io::Result<()> {
+    if FileInfo::new(&source.to_path_buf())?.hash != file_info.hash {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} changed", source.display()),
+        ));
+    }
+    if std::fs::rename(source, &file_info.path).is_err() {
+        write_file(&file_info.path, &std::fs::read(source)?, &file_info.hash)?;
+        std::fs::remove_file(source)?;
+    }
+    Ok(())
+}
 
-                // let file_url = format!("http://{}/file/self", server);
-                // let res = client.get(file_url.as_str()).send().await?;
+/// Indexes the files among `entries` and in the directories among them by
+/// content hash. Only files with one of `sizes` are read, no other file has
+/// content to reuse. Files which can't be read are left out.
+fn index_local_files(
+    entries: &[PathBuf],
+    sizes: &HashSet<u64>,
+    index: &mut HashMap<String, PathBuf>,
+) {
+    for path in entries {
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            continue;
+        };
+        if meta.is_dir() {
+            let children: Vec<_> = match std::fs::read_dir(path) {
+                Ok(read_dir) => read_dir.flatten().map(|entry| entry.path()).collect(),
+                Err(_) => continue,
+            };
+            index_local_files(&children, sizes, index);
+        } else if meta.is_file() && sizes.contains(&meta.len()) {
+            match FileInfo::new(path) {
+                Ok(file_info) => {
+                    index.entry(file_info.hash).or_insert_with(|| path.clone());
+                }
+                Err(err) => debug!(path = %path.display(), "not indexed: {}", err),
             }
         }
-        Response::Error(err) => {
-            panic!("{}", err);
+    }
+}
+
+/// Removes the file or link at `path`, if there is one. Directories are left
+/// alone, replacing them fails later with a clear error.
+fn remove_if_exists(path: &std::path::Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.is_dir() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+fn is_symlink(path: &std::path::Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+}
+
+/// Fails if `path` is not below `root` or one of the directories between them
+/// is a symlink, so that nothing is written outside `root` through a link.
+fn check_parents(root: &std::path::Path, path: &std::path::Path) -> std::io::Result<()> {
+    let relative = path.strip_prefix(root).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is outside {}", path.display(), root.display()),
+        )
+    })?;
+    let mut parent = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if !matches!(component, std::path::Component::Normal(_)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unsafe path {}", path.display()),
+            ));
         }
-        _ => {
-            panic!("unexpected response");
+        if components.peek().is_none() {
+            break;
+        }
+        parent.push(component);
+        if is_symlink(&parent) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "refusing to write {} through link {}",
+                    path.display(),
+                    parent.display()
+                ),
+            ));
         }
     }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(
+    path: &std::path::Path,
+    target: &std::path::Path,
+    _symlink: &SymlinkInfo,
+) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn create_symlink(
+    path: &std::path::Path,
+    target: &std::path::Path,
+    symlink: &SymlinkInfo,
+) -> std::io::Result<()> {
+    if symlink.is_dir {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+/// Whether the directory at `path` lacks the server's permissions or mtime.
+fn dir_differs(path: &std::path::Path, dir_info: &DirInfo) -> bool {
+    match std::fs::metadata(path) {
+        Ok(meta) => {
+            (dir_info.mode.is_some() && unix_mode(&meta) != dir_info.mode)
+                || unix_mtime(&meta) != dir_info.last_modified
+        }
+        Err(_) => true,
+    }
+}
+
+/// Applies the server's permissions and mtime to the directory at `path`.
+#[cfg(unix)]
+fn set_dir_metadata(path: &std::path::Path, dir_info: &DirInfo) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = dir_info.mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    let mtime = UNIX_EPOCH + Duration::from_secs(dir_info.last_modified);
+    std::fs::File::open(path)?.set_modified(mtime)
+}
+
+/// Directories can't be opened as files on Windows, so only the read-only
+/// flag is applied there.
+#[cfg(not(unix))]
+fn set_dir_metadata(path: &std::path::Path, dir_info: &DirInfo) -> std::io::Result<()> {
+    if let Some(mode) = dir_info.mode {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o200 == 0);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// Collects the local entries under `dir` which are not in `expected`.
+/// Directories are listed without their content. With `best_effort`,
+/// unreadable directories are logged and left out instead of failing.
+fn find_extra_entries(
+    dir: &std::path::Path,
+    expected: &HashSet<std::path::PathBuf>,
+    best_effort: bool,
+    result: &mut Vec<std::path::PathBuf>,
+) -> std::io::Result<()> {
+    let read_dir = || -> std::io::Result<Vec<(PathBuf, bool)>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok((entry.path(), entry.file_type()?.is_dir()))
+            })
+            .collect()
+    };
+    let entries = match read_dir() {
+        Ok(entries) => entries,
+        Err(err) if best_effort => {
+            warn!(path = %dir.display(), "not looked through for reuse: {}", err);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    for (path, is_dir) in entries {
+        if !expected.contains(&path) {
+            result.push(path);
+        } else if is_dir {
+            find_extra_entries(&path, expected, best_effort, result)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn client_main(options: &ClientOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let server = options.server.as_str();
+    let dir = options.dir.as_str();
+    let bwlimit = Arc::new(TokenBucket::new(options.bwlimit));
+    let received = Arc::new(AtomicU64::new(0));
+    if options.dry_run && options.prints_plan() {
+        println!("dry run");
+    }
+    info!("sync {} from {}", dir, server);
+    let total_clock = Instant::now();
+    let mut report = SyncReport::new(server, dir, options.dry_run);
+
+    //auth request
+    let mut client = match connect(server, &options.auth_key, &bwlimit, &received) {
+        Ok(client) => client,
+        Err(err) => {
+            error!(%server, "connect failed: {}", err);
+            report.finish(total_clock.elapsed(), Some(err.to_string()));
+            write_report(options, &report)?;
+            return Err(err);
+        }
+    };
+
+    if options.self_update && std::env::var_os(release::UPDATED_ENV).is_none() {
+        // the updated binary syncs and reports in our place
+        if let Some(status) = self_update(&mut client, options)? {
+            if !status.success() {
+                return Err(format!("updated client failed: {}", status).into());
+            }
+            return Ok(());
+        }
+    }
+
+    let result = sync(options, client, &bwlimit, &received, &mut report);
+    report.finish(
+        total_clock.elapsed(),
+        result.as_ref().err().map(|err| err.to_string()),
+    );
+    write_report(options, &report)?;
+    if result.is_ok() && options.prints_plan() {
+        println!(
+            "total size: {:?}, done in {}.",
+            human_size(report.total_bytes),
+            human_duration(total_clock.elapsed()),
+        );
+    }
+    result
+}
+
+fn write_report(
+    options: &ClientOptions,
+    report: &SyncReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(format) = options.report_format() {
+        report.write(format, options.report_file.as_deref())?;
+    }
+    Ok(())
+}
+
+/// Path of `path` relative to the synced directory, for the report.
+fn relative(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// The report entry of the local entry at `path`, taken before deleting it.
+/// A directory has the size of the files in it, only files have a hash.
+fn deleted_entry(root: &Path, path: &Path) -> FileEntry {
+    let (size, hash) = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_file() => match FileInfo::new(&path.to_path_buf()) {
+            Ok(file_info) => (file_info.size, file_info.hash),
+            Err(_) => (meta.len(), String::new()),
+        },
+        Ok(meta) if meta.is_dir() => (tree_size(path), String::new()),
+        _ => (0, String::new()),
+    };
+    FileEntry {
+        path: relative(root, path),
+        size,
+        hash,
+        duration_ms: None,
+    }
+}
+
+/// Total size of the files below `dir`, not following links.
+fn tree_size(dir: &Path) -> u64 {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    read_dir
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => tree_size(&entry.path()),
+            Ok(meta) if meta.is_file() => meta.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Brings the local directory in line with the server, recording what was
+/// planned and done in `report`.
+fn sync(
+    options: &ClientOptions,
+    mut client: Connection,
+    bwlimit: &Arc<TokenBucket>,
+    received: &Arc<AtomicU64>,
+    report: &mut SyncReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = options.server.as_str();
+    let dir = options.dir.as_str();
+    let auth_key = options.auth_key.as_str();
+    let dry_run = options.dry_run;
+    let jobs = options.jobs;
 
     //read dir_info
-    let request = Request::GetDirInfo("".to_string());
+    let request = dir_info_request(options, &mut client, report)?;
     let response = do_request(&request, &mut client)?;
     match response {
         Response::DirInfo(mut base_info) => {
             let mut local_root = std::path::Path::new(dir).to_path_buf();
             if local_root.is_relative() {
-                local_root = std::fs::canonicalize(&local_root)?;
+                // a missing directory is created by the sync
+                local_root = std::fs::canonicalize(&local_root)
+                    .or_else(|_| std::path::absolute(&local_root))?;
+            }
+            for warning in base_info.set_all_file_paths(&local_root, options.names)? {
+                warn!("{}", warning);
+                report.warnings.push(warning);
             }
-            base_info.set_all_file_paths(&local_root);
             let base_file_info_hashes = &base_info.flat_hashes();
-            let mut total_bytes: u64 = 0;
 
-            for (path_hash, file_info) in base_file_info_hashes {
-                let local_file_info = FileInfo::new(&file_info.path);
-                if local_file_info.is_err() || local_file_info.unwrap().hash != file_info.hash {
-                    total_bytes += file_info.size;
-                    if dry_run {
+            // the first file of each hardlink group by path is downloaded,
+            // the others are linked to it
+            let mut hardlink_leaders: HashMap<&str, &FileInfo> = HashMap::new();
+            for file_info in base_file_info_hashes.values() {
+                if let Some(group) = &file_info.hardlink_group {
+                    let leader = hardlink_leaders.entry(group).or_insert(file_info);
+                    if file_info.path < leader.path {
+                        *leader = file_info;
+                    }
+                }
+            }
+
+            // local paths of the files to download which exist, to tell
+            // updates from additions
+            let mut existing = HashSet::new();
+            // local files which already have their content, by content hash
+            let mut local_contents: HashMap<&str, &Path> = HashMap::new();
+            let mut changed: Vec<&FileInfo> = Vec::new();
+            let mut hardlinks: Vec<(&FileInfo, &FileInfo)> = Vec::new();
+            // files changed locally since the last sync
+            let state = State::load(&local_root)?;
+            let mut modified: Vec<PathBuf> = Vec::new();
+            let overwrite = options.local_changes == LocalChangePolicy::Overwrite;
+            for file_info in base_file_info_hashes.values() {
+                let path = relative(&local_root, &file_info.path);
+                let local_hash = FileInfo::new(&file_info.path).ok().map(|local| local.hash);
+                if local_hash.as_ref() == Some(&file_info.hash) {
+                    local_contents
+                        .entry(&file_info.hash)
+                        .or_insert(&file_info.path);
+                    existing.insert(path);
+                    continue;
+                }
+                if let Some(local_hash) = local_hash {
+                    if state.is_modified(&path, &local_hash) {
+                        modified.push(file_info.path.clone());
+                        if !overwrite {
+                            continue;
+                        }
+                    }
+                    existing.insert(path);
+                }
+                let leader = file_info
+                    .hardlink_group
+                    .as_deref()
+                    .map(|group| hardlink_leaders[group])
+                    .filter(|leader| leader.path != file_info.path);
+                match leader {
+                    Some(leader) => hardlinks.push((leader, file_info)),
+                    None => changed.push(file_info),
+                }
+            }
+            // files linked to a kept file get the server's content instead
+            if !overwrite {
+                hardlinks.retain(|(leader, file_info)| {
+                    let kept = modified.contains(&leader.path);
+                    if kept {
+                        changed.push(file_info);
+                    }
+                    !kept
+                });
+            }
+            changed.sort_by(|a, b| a.path.cmp(&b.path));
+            hardlinks.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+
+            let mut symlinks = Vec::new();
+            for symlink in base_info.flat_symlinks() {
+                let Some(target) = symlink.local_target() else {
+                    warn!(
+                        path = %symlink.path.display(),
+                        target = %symlink.target,
+                        "skip symlink: target not representable"
+                    );
+                    report.skipped.push(SkippedEntry {
+                        path: relative(&local_root, &symlink.path),
+                        reason: format!("target {} not representable", symlink.target),
+                    });
+                    continue;
+                };
+                if std::fs::read_link(&symlink.path).ok().as_ref() != Some(&target) {
+                    symlinks.push((&symlink.path, target, symlink));
+                }
+            }
+            symlinks.sort_by(|a, b| a.0.cmp(b.0));
+
+            let request = Request::GetScanReport;
+            if let Response::ScanReport(scan_report) = do_request(&request, &mut client)? {
+                if !scan_report.skipped.is_empty() {
+                    warn!(
+                        "server skipped {} unreadable entries",
+                        scan_report.skipped.len()
+                    );
+                }
+                for skipped in &scan_report.skipped {
+                    debug!(path = %skipped.path, reason = %skipped.reason, "server skipped entry");
+                }
+                report.skipped.extend(scan_report.skipped);
+            }
+
+            let dirs: Vec<_> = base_info
+                .flat_dirs()
+                .into_iter()
+                .map(|d| (&d.path, d))
+                .collect();
+            let new_dirs: Vec<_> = dirs[1..]
+                .iter()
+                .filter(|(path, _)| !path.is_dir())
+                .collect();
+
+            // local entries which are not on the server, deleted with
+            // --delete and else looked through for content to reuse
+            let mut extras = Vec::new();
+            // only --delete needs every extra entry, reuse is best effort
+            // and only looks when there is something to download
+            let reuse = options.reuse != ReusePolicy::Off && !changed.is_empty();
+            if (options.delete || reuse) && local_root.is_dir() {
+                let mut expected: HashSet<std::path::PathBuf> = HashSet::new();
+                expected.extend(base_file_info_hashes.values().map(|f| f.path.clone()));
+                expected.extend(
+                    base_info
+                        .flat_symlinks()
+                        .into_iter()
+                        .map(|l| l.path.clone()),
+                );
+                expected.extend(dirs.iter().map(|d| d.0.clone()));
+                find_extra_entries(&local_root, &expected, !options.delete, &mut extras)?;
+                extras.sort();
+            }
+            // backups and their journal are no part of the sync
+            let journal = Journal::load(&local_root)?;
+            let mut kept: Vec<PathBuf> = journal.backups().map(Path::to_path_buf).collect();
+            kept.push(local_root.join(backup::JOURNAL));
+            kept.push(local_root.join(state::STATE));
+            kept.extend(
+                options
+                    .backup_dir
+                    .as_deref()
+                    .map(std::path::absolute)
+                    .transpose()?,
+            );
+            extras.retain(|path| !kept.iter().any(|kept| path.starts_with(kept)));
+            let mut extra_contents = HashMap::new();
+            if reuse {
+                let sizes = changed.iter().map(|f| f.size).collect();
+                index_local_files(&extras, &sizes, &mut extra_contents);
+            }
+
+            // each content is downloaded once, files whose content is in the
+            // local directory already or downloaded for another file are
+            // created from that file. With --delete, local files which are
+            // not on the server any more are moved where their content is.
+            let mut files: Vec<&FileInfo> = Vec::new();
+            let mut reused: Vec<(&Path, &FileInfo)> = Vec::new();
+            let mut moves: Vec<(&Path, &FileInfo)> = Vec::new();
+            let mut downloaded: HashMap<&str, &Path> = HashMap::new();
+            for file_info in changed {
+                let source = local_contents
+                    .get(file_info.hash.as_str())
+                    .or_else(|| downloaded.get(file_info.hash.as_str()))
+                    .copied();
+                let extra = extra_contents.get(&file_info.hash);
+                match (source, extra) {
+                    (Some(source), _) if reuse => reused.push((source, file_info)),
+                    (None, Some(extra)) if options.delete => {
+                        // later files with this content are copied from here
+                        downloaded.insert(&file_info.hash, &file_info.path);
+                        moves.push((extra, file_info));
+                    }
+                    (None, Some(extra)) => reused.push((extra, file_info)),
+                    _ => {
+                        downloaded.insert(&file_info.hash, &file_info.path);
+                        report.total_bytes += file_info.size;
+                        files.push(file_info);
+                    }
+                }
+            }
+
+            // moved files are gone from their old place, directories holding
+            // them are only deleted once they are moved
+            let mut deletes = Vec::new();
+            let mut later_deletes = Vec::new();
+            if options.delete {
+                for path in &extras {
+                    if moves.iter().any(|(source, _)| source == path) {
+                        continue;
+                    }
+                    if moves.iter().any(|(source, _)| source.starts_with(path)) {
+                        later_deletes.push(path);
+                    } else {
+                        deletes.push(path);
+                    }
+                }
+                // synced files changed locally are kept with their directory
+                let candidates: HashSet<&Path> = deletes
+                    .iter()
+                    .chain(&later_deletes)
+                    .map(|p| p.as_path())
+                    .collect();
+                let mut kept_deletes = HashSet::new();
+                for path in state.files.keys() {
+                    let local = local_root.join(path);
+                    let Some(candidate) = local.ancestors().find(|a| candidates.contains(a)) else {
+                        continue;
+                    };
+                    let local_hash = FileInfo::new(&local).ok().map(|local| local.hash);
+                    if local_hash.is_some_and(|hash| state.is_modified(path, &hash)) {
+                        kept_deletes.insert(candidate.to_path_buf());
+                        modified.push(local);
+                    }
+                }
+                if !overwrite {
+                    deletes.retain(|path| !kept_deletes.contains(*path));
+                    later_deletes.retain(|path| !kept_deletes.contains(*path));
+                }
+            }
+
+            modified.sort();
+            let action = match options.local_changes {
+                LocalChangePolicy::Overwrite => "overwrite",
+                LocalChangePolicy::Skip => "keep",
+                LocalChangePolicy::Abort => "abort",
+            };
+            for path in &modified {
+                if dry_run && options.prints_plan() {
+                    println!("modified locally: {:?} ({})", path, action);
+                } else {
+                    warn!(path = %path.display(), action, "modified locally since the last sync");
+                }
+                report.modified.push(relative(&local_root, path));
+            }
+            if options.local_changes == LocalChangePolicy::Abort && !modified.is_empty() {
+                return Err(format!(
+                    "{} files modified locally since the last sync, see --local-changes",
+                    modified.len()
+                )
+                .into());
+            }
+
+            let file_entry = |file_info: &FileInfo, duration: Option<Duration>| FileEntry {
+                path: relative(&local_root, &file_info.path),
+                size: file_info.size,
+                hash: file_info.hash.clone(),
+                duration_ms: duration.map(|d| d.as_millis() as u64),
+            };
+            let record_file = |report: &mut SyncReport, entry: FileEntry| {
+                if existing.contains(entry.path.as_str()) {
+                    report.updated.push(entry);
+                } else {
+                    report.added.push(entry);
+                }
+            };
+            let hardlink_entry = |leader: &FileInfo, file_info: &FileInfo| LinkEntry {
+                path: relative(&local_root, &file_info.path),
+                target: relative(&local_root, &leader.path),
+                kind: "hardlink",
+            };
+            let reuse_entry = |source: &Path, file_info: &FileInfo, kind| ReuseEntry {
+                path: relative(&local_root, &file_info.path),
+                source: relative(&local_root, source),
+                kind,
+            };
+            let move_entry = |source: &Path, file_info: &FileInfo| MoveEntry {
+                path: relative(&local_root, &file_info.path),
+                source: relative(&local_root, source),
+            };
+            let symlink_entry = |path: &Path, target: &Path| LinkEntry {
+                path: relative(&local_root, path),
+                target: target.to_string_lossy().to_string(),
+                kind: "symlink",
+            };
+
+            if dry_run {
+                let print = options.prints_plan();
+                for path in deletes.iter().chain(&later_deletes) {
+                    if print {
+                        println!("delete: {:?}", path);
+                    }
+                    report.deleted.push(deleted_entry(&local_root, path));
+                }
+                for (path, _) in &new_dirs {
+                    if print {
+                        println!("create dir: {:?}", path);
+                    }
+                    report.created_dirs.push(relative(&local_root, path));
+                }
+                for file_info in &files {
+                    if print {
                         println!(
                             "get file: {:?} ({})",
                             file_info.path,
                             human_size(file_info.size)
                         );
-                        continue;
                     }
-                    let download_clock = Instant::now();
-                    let request = Request::GetFile(path_hash.to_string());
-                    let response = do_request(&request, &mut client)?;
-                    match response {
-                        Response::File(content) => {
-                            if verbose {
-                                println!(
-                                    "download {} ({}) in {}",
-                                    file_info.path.display(),
-                                    human_size(file_info.size),
-                                    human_duration(download_clock.elapsed()),
-                                );
+                    record_file(report, file_entry(file_info, None));
+                }
+                for (source, file_info) in &moves {
+                    if print {
+                        println!("move file: {:?} <= {:?}", file_info.path, source);
+                    }
+                    record_file(report, file_entry(file_info, None));
+                    report.moved.push(move_entry(source, file_info));
+                }
+                let kind = match options.reuse {
+                    ReusePolicy::Hardlink => "hardlink",
+                    _ => "copy",
+                };
+                for (source, file_info) in &reused {
+                    if print {
+                        println!("{} file: {:?} <= {:?}", kind, file_info.path, source);
+                    }
+                    record_file(report, file_entry(file_info, None));
+                    report.reused.push(reuse_entry(source, file_info, kind));
+                }
+                for (leader, file_info) in &hardlinks {
+                    if print {
+                        println!("link file: {:?} => {:?}", file_info.path, leader.path);
+                    }
+                    report.links.push(hardlink_entry(leader, file_info));
+                }
+                for (path, target, _) in &symlinks {
+                    if print {
+                        println!("symlink: {:?} -> {:?}", path, target);
+                    }
+                    report.links.push(symlink_entry(path, target));
+                }
+            } else {
+                // never write through links in the local tree, checked for
+                // every path before anything is removed
+                let targets = new_dirs.iter().map(|d| d.0.as_path());
+                let targets = targets.chain(files.iter().map(|f| f.path.as_path()));
+                let targets = targets.chain(reused.iter().map(|r| r.1.path.as_path()));
+                let targets = targets.chain(moves.iter().flat_map(|m| [m.0, m.1.path.as_path()]));
+                let targets = targets.chain(hardlinks.iter().map(|h| h.1.path.as_path()));
+                let targets =
+                    targets.chain(deletes.iter().chain(&later_deletes).map(|p| p.as_path()));
+                for path in targets.chain(symlinks.iter().map(|l| l.0.as_path())) {
+                    check_parents(&local_root, path)?;
+                }
+
+                std::fs::create_dir_all(&local_root)?;
+                let backup = options.backs_up().then(|| {
+                    let suffix = options.backup_suffix.as_deref().unwrap_or("");
+                    Backup::new(&local_root, options.backup_dir.as_deref(), suffix, journal)
+                });
+                let backup = backup.as_ref();
+                let delete = |report: &mut SyncReport, path: &Path| -> std::io::Result<()> {
+                    let entry = deleted_entry(&local_root, path);
+                    if let Some(backup) = backup {
+                        backup.delete(path)?;
+                    } else if std::fs::symlink_metadata(path)?.is_dir() {
+                        std::fs::remove_dir_all(path)?;
+                    } else {
+                        std::fs::remove_file(path)?;
+                    }
+                    debug!(path = %path.display(), "deleted");
+                    report.deleted.push(entry);
+                    Ok(())
+                };
+                for path in &deletes {
+                    delete(report, path)?;
+                }
+                // create directories up front so workers only write files
+                for (path, _) in &new_dirs {
+                    std::fs::create_dir_all(path)?;
+                    if let Some(backup) = backup {
+                        backup.created_dir(path);
+                    }
+                    debug!(path = %path.display(), "created dir");
+                    report.created_dirs.push(relative(&local_root, path));
+                }
+                for (source, file_info) in &moves {
+                    let hash = Some(file_info.hash.as_str());
+                    match replace_entry(backup, &file_info.path, hash, || {
+                        move_file(source, file_info)
+                    }) {
+                        Ok(()) => {
+                            debug!(
+                                path = %file_info.path.display(),
+                                source = %source.display(),
+                                "moved local file"
+                            );
+                            record_file(report, file_entry(file_info, None));
+                            report.moved.push(move_entry(source, file_info));
+                            if let Some(backup) = backup {
+                                backup.moved(source, &file_info.path, &file_info.hash);
                             }
-                            let p = file_info.path.parent().unwrap();
-                            std::fs::create_dir_all(p).unwrap();
-                            write_compressed_file(&file_info.path, content.as_slice())?;
                         }
-                        _ => {}
+                        Err(err) => {
+                            warn!(source = %source.display(), "move failed, downloading: {}", err);
+                            report.total_bytes += file_info.size;
+                            files.push(file_info);
+                        }
                     }
                 }
-            }
+                for path in &later_deletes {
+                    delete(report, path)?;
+                }
+                if let Some(backup) = backup {
+                    backup.commit()?;
+                }
+                let batches = plan_batches(&files);
+                let mut clients = vec![client];
+                for _ in 1..jobs.clamp(1, batches.len().max(1)) {
+                    clients.push(connect(server, auth_key, bwlimit, received)?);
+                }
+                let progress = Progress::new(report.total_bytes, files.len(), received.clone());
+                let done = AtomicBool::new(false);
+                let results = std::thread::scope(|scope| {
+                    if options.shows_progress() && !files.is_empty() {
+                        scope.spawn(|| progress.display(&done));
+                    }
+                    let results = download_files(clients, &files, &batches, &progress, backup);
+                    done.store(true, Ordering::SeqCst);
+                    results
+                });
+                for (index, result) in results {
+                    let file_info = files[index];
+                    match result {
+                        Ok(duration) => record_file(report, file_entry(file_info, Some(duration))),
+                        Err(err) => {
+                            error!(path = %file_info.path.display(), "download failed: {}", err);
+                            report.failed.push(FailedEntry {
+                                path: relative(&local_root, &file_info.path),
+                                error: err,
+                            });
+                        }
+                    }
+                }
+                for (source, file_info) in &reused {
+                    let clock = Instant::now();
+                    let hash = Some(file_info.hash.as_str());
+                    match replace_entry(backup, &file_info.path, hash, || {
+                        reuse_file(source, file_info, options.reuse)
+                    }) {
+                        Ok(kind) => {
+                            debug!(
+                                path = %file_info.path.display(),
+                                source = %source.display(),
+                                kind,
+                                "reused local file"
+                            );
+                            record_file(report, file_entry(file_info, Some(clock.elapsed())));
+                            report.reused.push(reuse_entry(source, file_info, kind));
+                        }
+                        Err(err) => {
+                            error!(path = %file_info.path.display(), "reuse failed: {}", err);
+                            report.failed.push(FailedEntry {
+                                path: relative(&local_root, &file_info.path),
+                                error: err.to_string(),
+                            });
+                        }
+                    }
+                }
+                // what the next sync compares local files with: the server's
+                // content, or for kept and unwritten files what the last sync left
+                let save_state = |unwritten: &HashSet<String>| {
+                    let mut synced = State {
+                        synced_at: chrono::Local::now().to_rfc3339(),
+                        ..Default::default()
+                    };
+                    for file_info in base_file_info_hashes.values() {
+                        let path = relative(&local_root, &file_info.path);
+                        let hash = if unwritten.contains(&path) {
+                            state.files.get(&path).cloned()
+                        } else {
+                            Some(file_info.hash.clone())
+                        };
+                        if let Some(hash) = hash {
+                            synced.files.insert(path, hash);
+                        }
+                    }
+                    for path in modified.iter().filter(|_| !overwrite) {
+                        let path = relative(&local_root, path);
+                        if let Some(hash) = state.files.get(&path) {
+                            synced.files.insert(path, hash.clone());
+                        }
+                    }
+                    synced.save(&local_root)
+                };
+                if !report.failed.is_empty() {
+                    // files linked to are not all there, so links are left out
+                    let mut unwritten: HashSet<String> =
+                        report.failed.iter().map(|f| f.path.clone()).collect();
+                    unwritten.extend(
+                        hardlinks
+                            .iter()
+                            .map(|(_, file_info)| relative(&local_root, &file_info.path)),
+                    );
+                    save_state(&unwritten)?;
+                    if let Some(backup) = backup {
+                        backup.commit()?;
+                    }
+                    return Err(format!(
+                        "{} of {} files failed",
+                        report.failed.len(),
+                        files.len() + reused.len()
+                    )
+                    .into());
+                }
 
-            println!(
-                "total size: {:?}, done in {}.",
-                human_size(total_bytes),
-                human_duration(total_clock.elapsed()),
-            );
+                for (leader, file_info) in &hardlinks {
+                    std::fs::create_dir_all(file_info.path.parent().unwrap())?;
+                    replace_entry(backup, &file_info.path, Some(&leader.hash), || {
+                        remove_if_exists(&file_info.path)?;
+                        std::fs::hard_link(&leader.path, &file_info.path)
+                    })?;
+                    debug!(
+                        path = %file_info.path.display(),
+                        leader = %leader.path.display(),
+                        "linked"
+                    );
+                    report.links.push(hardlink_entry(leader, file_info));
+                }
+                for (path, target, symlink) in &symlinks {
+                    std::fs::create_dir_all(path.parent().unwrap())?;
+                    replace_entry(backup, path, None, || {
+                        remove_if_exists(path)?;
+                        create_symlink(path, target, symlink)
+                    })?;
+                    debug!(path = %path.display(), target = %target.display(), "symlinked");
+                    report.links.push(symlink_entry(path, target));
+                }
+
+                save_state(&HashSet::new())?;
+                if let Some(backup) = backup {
+                    backup.commit()?;
+                }
+                // children first, writing into a directory changes its mtime
+                for (path, dir_info) in dirs.iter().rev() {
+                    if dir_differs(path, dir_info) {
+                        set_dir_metadata(path, dir_info)?;
+                    }
+                }
+            }
         }
-        _ => {}
+        Response::Error(err) => return Err(err.into()),
+        _ => return Err("unexpected response".into()),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_info(size: u64) -> FileInfo {
+        FileInfo {
+            path: Default::default(),
+            path_hash: String::new(),
+            name: Default::default(),
+            size,
+            last_modified: 0,
+            hash: String::new(),
+            hardlink_group: None,
+        }
+    }
+
+    /// Serves `dir` on a free local port for the rest of the test run and
+    /// returns the address.
+    fn serve(dir: &Path) -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let options = crate::server::ServerOptions {
+            addr: addr.clone(),
+            target_dir: dir.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            crate::server::server_main(&options, Box::new(|| Err("no reload".to_string())))
+        });
+        for _ in 0..100 {
+            if TcpStream::connect(&addr).is_ok() {
+                return addr;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("server on {} didn't start", addr);
+    }
+
+    fn options(server: &str, dir: &Path) -> ClientOptions {
+        ClientOptions {
+            server: server.to_string(),
+            dir: dir.to_string_lossy().to_string(),
+            progress: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_local_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (served, local) = (tmp.path().join("served"), tmp.path().join("local"));
+        std::fs::create_dir_all(&served).unwrap();
+        std::fs::write(served.join("a.txt"), "new").unwrap();
+        std::fs::write(served.join("b.txt"), "b").unwrap();
+        let server = serve(&served);
+        let prepare = || {
+            let _ = std::fs::remove_dir_all(&local);
+            std::fs::create_dir_all(local.join("old")).unwrap();
+            std::fs::write(local.join("a.txt"), "mine").unwrap();
+            std::fs::write(local.join("old/x.txt"), "mine too").unwrap();
+            let mut state = State::default();
+            state.files.insert("a.txt".to_string(), get_hash(b"synced"));
+            state
+                .files
+                .insert("old/x.txt".to_string(), get_hash(b"synced"));
+            state.save(&local).unwrap();
+        };
+        let sync_with = |local_changes| {
+            let options = ClientOptions {
+                delete: true,
+                local_changes,
+                ..options(&server, &local)
+            };
+            client_main(&options)
+        };
+        let read = |path: &str| std::fs::read_to_string(local.join(path)).ok();
+
+        prepare();
+        let err = sync_with(LocalChangePolicy::Abort).unwrap_err();
+        assert!(err.to_string().starts_with("2 files modified locally"));
+        assert_eq!(read("a.txt").as_deref(), Some("mine"));
+        assert_eq!(read("b.txt"), None);
+
+        prepare();
+        sync_with(LocalChangePolicy::Skip).unwrap();
+        assert_eq!(read("a.txt").as_deref(), Some("mine"));
+        assert_eq!(read("old/x.txt").as_deref(), Some("mine too"));
+        assert_eq!(read("b.txt").as_deref(), Some("b"));
+        // still kept by the next sync
+        sync_with(LocalChangePolicy::Skip).unwrap();
+        assert_eq!(read("old/x.txt").as_deref(), Some("mine too"));
+
+        sync_with(LocalChangePolicy::Overwrite).unwrap();
+        assert_eq!(read("a.txt").as_deref(), Some("new"));
+        assert!(!local.join("old").exists());
+        let state = State::load(&local).unwrap();
+        assert_eq!(state.files.keys().collect::<Vec<_>>(), ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn test_find_extra_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("kept")).unwrap();
+        std::fs::write(root.join("kept/a.txt"), "a").unwrap();
+        std::fs::write(root.join("b.txt"), "b").unwrap();
+        let expected = HashSet::from([root.join("kept")]);
+        let mut extras = Vec::new();
+        find_extra_entries(root, &expected, false, &mut extras).unwrap();
+        extras.sort();
+        assert_eq!(extras, [root.join("b.txt"), root.join("kept/a.txt")]);
+
+        // a directory which can't be read only fails the walk --delete needs
+        let missing = root.join("missing");
+        let mut extras = Vec::new();
+        assert!(find_extra_entries(&missing, &expected, false, &mut extras).is_err());
+        find_extra_entries(&missing, &expected, true, &mut extras).unwrap();
+        assert!(extras.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_in_the_way_fails_before_deleting() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (served, local) = (tmp.path().join("served"), tmp.path().join("local"));
+        let elsewhere = tmp.path().join("elsewhere");
+        std::fs::create_dir_all(served.join("d")).unwrap();
+        std::fs::write(served.join("d/x.txt"), "x").unwrap();
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        std::fs::write(local.join("old.txt"), "old").unwrap();
+        std::os::unix::fs::symlink(&elsewhere, local.join("d")).unwrap();
+        let server = serve(&served);
+
+        let options = ClientOptions {
+            delete: true,
+            ..options(&server, &local)
+        };
+        let err = client_main(&options).unwrap_err();
+        assert!(err.to_string().contains("through link"), "{}", err);
+        assert!(local.join("old.txt").exists());
+        assert!(!elsewhere.join("x.txt").exists());
+    }
+
+    #[test]
+    fn test_deleted_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("old/sub")).unwrap();
+        std::fs::write(root.join("old/a.txt"), "abc").unwrap();
+        std::fs::write(root.join("old/sub/b.txt"), "de").unwrap();
+
+        let entry = deleted_entry(root, &root.join("old/a.txt"));
+        assert_eq!((entry.path.as_str(), entry.size), ("old/a.txt", 3));
+        assert_eq!(entry.hash, get_hash(b"abc"));
+        let entry = deleted_entry(root, &root.join("old"));
+        assert_eq!((entry.path.as_str(), entry.size), ("old", 5));
+        assert_eq!(entry.hash, "");
+    }
+
+    #[test]
+    fn test_connect_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        // a port nothing listens on once the listener is gone
+        let server = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let report_file = tmp.path().join("report.json");
+        let options = ClientOptions {
+            report_file: Some(report_file.clone()),
+            ..options(&server, tmp.path())
+        };
+        assert!(client_main(&options).is_err());
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report_file).unwrap()).unwrap();
+        assert_eq!(report["success"], false);
+    }
+
+    #[test]
+    fn test_sync_into_missing_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let served = tmp.path().join("served");
+        // no directory below the root which would create it on the way
+        std::fs::create_dir_all(&served).unwrap();
+        std::fs::write(served.join("top.txt"), "top").unwrap();
+        let server = serve(&served);
+
+        let local = tmp.path().join("missing/local");
+        client_main(&options(&server, &local)).unwrap();
+        assert_eq!(std::fs::read(local.join("top.txt")).unwrap(), b"top");
+        // the root gets the served directory's mtime like the others
+        let mtime = |path: &Path| unix_mtime(&std::fs::metadata(path).unwrap());
+        assert_eq!(mtime(&local), mtime(&served));
+    }
+
+    #[test]
+    fn test_failed_download_keeps_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (served, local) = (tmp.path().join("served"), tmp.path().join("local"));
+        std::fs::create_dir_all(&served).unwrap();
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::write(served.join("a.txt"), "new").unwrap();
+        std::fs::write(served.join("b.txt"), "new b").unwrap();
+        std::fs::write(local.join("a.txt"), "old").unwrap();
+        std::fs::write(local.join("b.txt"), "old b").unwrap();
+        let server = serve(&served);
+        // changed after the server listed it, so its download fails
+        std::fs::write(served.join("a.txt"), "newer").unwrap();
+
+        let options = ClientOptions {
+            backup_suffix: Some("~".to_string()),
+            ..options(&server, &local)
+        };
+        assert!(client_main(&options).is_err());
+        assert_eq!(std::fs::read(local.join("a.txt")).unwrap(), b"old");
+        assert_eq!(std::fs::read(local.join("b.txt")).unwrap(), b"new b");
+        // only the replaced file is journaled and backed up
+        let journal = Journal::load(&local).unwrap();
+        let entries = &journal.sessions[0].entries;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "b.txt");
+        assert_eq!(journal.backups().count(), 1);
+
+        let restored = backup::restore(&local).unwrap().unwrap();
+        assert_eq!(restored.undone, 1);
+        assert_eq!(std::fs::read(local.join("b.txt")).unwrap(), b"old b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_parents() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::os::unix::fs::symlink("/tmp", root.join("link")).unwrap();
+
+        assert!(check_parents(root, &root.join("dir/file")).is_ok());
+        assert!(check_parents(root, &root.join("link")).is_ok());
+        assert!(check_parents(root, &root.join("link/file")).is_err());
+        assert!(check_parents(root, &root.join("dir/../../file")).is_err());
+        assert!(check_parents(root, std::path::Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_reuse_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("a.txt");
+        std::fs::write(&source, "same").unwrap();
+        let mut target = file_info(4);
+        target.path = dir.join("b.txt");
+        target.hash = get_hash(b"same");
+
+        assert_eq!(
+            reuse_file(&source, &target, ReusePolicy::Copy).unwrap(),
+            "copy"
+        );
+        assert_eq!(std::fs::read(&target.path).unwrap(), b"same");
+        target.path = dir.join("c.txt");
+        assert_eq!(
+            reuse_file(&source, &target, ReusePolicy::Hardlink).unwrap(),
+            "hardlink"
+        );
+        assert_eq!(std::fs::read(&target.path).unwrap(), b"same");
+
+        // the source changed since it was compared
+        std::fs::write(&source, "other").unwrap();
+        target.path = dir.join("d.txt");
+        assert!(reuse_file(&source, &target, ReusePolicy::Hardlink).is_err());
+        assert!(!target.path.exists());
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_index_and_move() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("old")).unwrap();
+        std::fs::write(dir.join("old/a.txt"), "moved").unwrap();
+        std::fs::write(dir.join("old/b.txt"), "other size").unwrap();
+
+        let mut index = HashMap::new();
+        index_local_files(&[dir.join("old")], &HashSet::from([5]), &mut index);
+        let hash = get_hash(b"moved");
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[&hash], dir.join("old/a.txt"));
+
+        let mut target = file_info(5);
+        target.path = dir.join("a.txt");
+        target.hash = hash;
+        move_file(&index[&target.hash], &target).unwrap();
+        assert_eq!(std::fs::read(&target.path).unwrap(), b"moved");
+        assert!(!dir.join("old/a.txt").exists());
+    }
+
+    #[test]
+    fn test_plan_batches() {
+        let small = file_info(10);
+        let large = file_info(BATCH_FILE_SIZE + 1);
+        let mut files = vec![&small, &large, &small];
+        assert_eq!(plan_batches(&files), vec![vec![1], vec![0, 2]]);
+
+        files = vec![&small; BATCH_MAX_FILES + 1];
+        let batches = plan_batches(&files);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[1], vec![BATCH_MAX_FILES]);
+    }
+}
@@ -1,4 +1,7 @@
+use crate::admin;
 use crate::fileinfo;
+use crate::history;
+use crate::release;
 use flate2::read;
 use flate2::write;
 use flate2::Compression;
@@ -13,11 +16,19 @@ pub fn compress_bytes(input: &[u8]) -> Vec<u8> {
     encoder.finish().unwrap()
 }
 
-pub fn decompress_bytes(input: &[u8]) -> Vec<u8> {
+/// Decompresses `input`, failing with `InvalidData` on a corrupt stream.
+pub fn decompress_bytes(input: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     let mut decoder = read::DeflateDecoder::new(input);
     let mut output = Vec::new();
-    decoder.read_to_end(&mut output).unwrap();
-    output
+    decoder
+        .read_to_end(&mut output)
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::InvalidInput => {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+            }
+            _ => err,
+        })?;
+    Ok(output)
 }
 
 use serde::{Deserialize, Serialize};
@@ -56,6 +67,22 @@ pub fn human_size(bytes_size: u64) -> String {
     }
 }
 
+/// Parses a size like `512`, `64K`, `1.5M` or `2GB` into bytes (1024 based).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_uppercase();
+    let number = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, unit) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1024u64),
+        Some('M') => (&number[..number.len() - 1], 1024 * 1024),
+        Some('G') => (&number[..number.len() - 1], 1024 * 1024 * 1024),
+        _ => (number, 1),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 => Ok((n * unit as f64) as u64),
+        _ => Err(format!("invalid size: {}", s)),
+    }
+}
+
 pub fn read_file_as_compressed(file_path: &std::path::Path) -> Result<Vec<u8>, std::io::Error> {
     let mut f = File::open(file_path)?;
     let mut buf = Vec::new();
@@ -65,14 +92,49 @@ pub fn read_file_as_compressed(file_path: &std::path::Path) -> Result<Vec<u8>, s
     Ok(buf)
 }
 
+/// Writes the decompressed `buf` to `file_path` if its content has `hash`.
 pub fn write_compressed_file(
     file_path: &std::path::Path,
     buf: &[u8],
+    hash: &str,
+) -> Result<(), std::io::Error> {
+    write_file(file_path, &decompress_bytes(buf)?, hash)
+}
+
+/// Writes `content` to `file_path` if it has `hash`. The content goes to a
+/// temporary file next to it first, which replaces `file_path` once complete.
+pub fn write_file(
+    file_path: &std::path::Path,
+    content: &[u8],
+    hash: &str,
 ) -> Result<(), std::io::Error> {
-    let mut f = File::create(file_path)?;
-    let decmpressed = decompress_bytes(buf);
-    f.write_all(decmpressed.as_slice())?;
-    Ok(())
+    if fileinfo::get_hash(content) != hash {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "content doesn't match its hash",
+        ));
+    }
+    let tmp_path = tmp_path(file_path);
+    // a new file, never one a link left at the temporary path points to
+    let _ = std::fs::remove_file(&tmp_path);
+    let result = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .and_then(|mut f| f.write_all(content))
+        .and_then(|_| std::fs::rename(&tmp_path, file_path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// The temporary file `file_path` is written to before it is replaced.
+pub fn tmp_path(file_path: &std::path::Path) -> std::path::PathBuf {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_path.file_name().unwrap_or_default());
+    tmp_name.push(".dirsync-tmp");
+    file_path.with_file_name(tmp_name)
 }
 
 #[derive(Debug, Clone)]
@@ -80,17 +142,86 @@ pub enum Error {
     BadRequest,
     BadResponse,
     NotFound(String),
+    Io(String),
+    /// The file no longer has the content the client asked for.
+    Changed(String),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Request {
-    Auth(String),
+    /// Auth key and a name identifying the client in server logs.
+    Auth(String, String),
     GetDirInfo(String),
+    /// `GetFileHash`, `GetFile` and `GetFiles` are only sent by older clients,
+    /// current ones request content with `GetBlob` and `GetBlobs`. They stay
+    /// served, and in place so the variants after them keep their encoding.
     GetFileHash(String),
-    GetFile(String),
+    /// Requests a file by path hash and content hash. The server answers
+    /// with exactly that content, or `Error::Changed` when the file has
+    /// other content by now.
+    GetFile(String, String),
+    /// Requests several files at once, the server answers with one `File` or
+    /// `Error` response per path and content hash, in order.
+    GetFiles(Vec<(String, String)>),
+    /// Requests the entries the server's last scan skipped.
+    GetScanReport,
+    /// Requests the client releases the server offers, answered with `Releases`.
+    ListReleases,
+    /// Requests the client release for a target and version, the latest one
+    /// without a version, answered with `Release`.
+    GetRelease(String, Option<String>),
+    /// Requests the binary of the release for a target and version, answered
+    /// with `File`.
+    GetReleaseFile(String, String),
+    /// Admin requests, which need the server's admin key. `AdminStatus` is
+    /// answered with `Status`, `AdminClients` with `Clients` and the others
+    /// with `Done`.
+    AdminStatus,
+    AdminClients,
+    /// Rescans the served directory now.
+    AdminRescan,
+    AdminFlushCache,
+    /// Reloads the server's config file.
+    AdminReload,
+    /// Requests content by its hash, whichever files have it. Answered with
+    /// `File`, or `Error::NotFound` when no file has that content any more.
+    GetBlob(String),
+    /// Requests several contents at once, answered with one `File` or `Error`
+    /// response per hash, in order.
+    GetBlobs(Vec<String>),
+    /// Requests the versions the server keeps in its history, answered with
+    /// `Versions`.
+    ListVersions,
+    /// Requests the file list of a version in the history, answered with
+    /// `DirInfo`. Its files are requested with `GetBlob`.
+    GetVersion(u64),
 }
 
 impl Request {
+    /// The variant name, for logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Auth(..) => "Auth",
+            Request::GetDirInfo(_) => "GetDirInfo",
+            Request::GetFileHash(_) => "GetFileHash",
+            Request::GetFile(..) => "GetFile",
+            Request::GetFiles(_) => "GetFiles",
+            Request::GetScanReport => "GetScanReport",
+            Request::ListReleases => "ListReleases",
+            Request::GetRelease(..) => "GetRelease",
+            Request::GetReleaseFile(..) => "GetReleaseFile",
+            Request::AdminStatus => "AdminStatus",
+            Request::AdminClients => "AdminClients",
+            Request::AdminRescan => "AdminRescan",
+            Request::AdminFlushCache => "AdminFlushCache",
+            Request::AdminReload => "AdminReload",
+            Request::GetBlob(_) => "GetBlob",
+            Request::GetBlobs(_) => "GetBlobs",
+            Request::ListVersions => "ListVersions",
+            Request::GetVersion(_) => "GetVersion",
+        }
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         let buf = bincode::serialize(self).unwrap();
         buf
@@ -108,6 +239,14 @@ pub enum Response {
     DirInfo(fileinfo::DirInfo),
     FileHash(String),
     File(Arc<Vec<u8>>),
+    ScanReport(fileinfo::ScanReport),
+    Release(release::Release),
+    Releases(Vec<release::Release>),
+    Status(admin::ServerStatus),
+    Clients(Vec<admin::ClientStatus>),
+    /// What an admin request did.
+    Done(String),
+    Versions(Vec<history::VersionInfo>),
     Error(String),
 }
 
@@ -147,7 +286,7 @@ impl Frame {
     pub fn read_from(reader: &mut impl Read) -> Result<Self, std::io::Error> {
         let mut buf = [0u8; 4];
         reader.read_exact(&mut buf)?;
-        let len: u32 = u32::from_be_bytes(buf);
+        let len: u32 = u32::from_le_bytes(buf);
         let mut frame = Frame {
             len,
             data: vec![0u8; len as usize],
@@ -205,9 +344,38 @@ mod tests {
 
     #[test]
     fn test_request() {
-        let request = Request::Auth("friday".to_string());
+        let request = Request::Auth("friday".to_string(), "host".to_string());
         let bin = request.encode();
         let r2 = Request::decode(bin.as_slice());
         assert_eq!(request, r2);
     }
+
+    #[test]
+    fn test_write_compressed_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "old").unwrap();
+        let buf = compress_bytes(b"new");
+
+        let err = write_compressed_file(&path, &buf, "0").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        let hash = fileinfo::get_hash(b"new");
+        let err = write_compressed_file(&path, &[0xff; 8], &hash).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+
+        write_compressed_file(&path, &buf, &fileinfo::get_hash(b"new")).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1.5mb"), Ok(1536 * 1024));
+        assert!(parse_size("fast").is_err());
+    }
 }
@@ -0,0 +1,232 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use tracing::Level;
+
+use crate::client::{LocalChangePolicy, ReusePolicy};
+use crate::common::parse_size;
+use crate::fileinfo::LinkPolicy;
+use crate::logging::{LogFormat, LogRotation};
+use crate::names::NamePolicy;
+use crate::ratelimit::Schedule;
+use crate::report::ReportFormat;
+
+/// Settings read from a TOML file. Everything is optional, flags given on the
+/// command line take precedence over the file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log: LogConfig,
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `error`, `warn`, `info`, `debug` or `trace`.
+    #[serde(deserialize_with = "parsed")]
+    pub level: Option<Level>,
+    #[serde(deserialize_with = "parsed")]
+    pub format: Option<LogFormat>,
+    pub file: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    pub rotation: Option<LogRotation>,
+}
+
+/// The `[server]` table, named like the flags of `dirsync server`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Option<String>,
+    pub dir: Option<String>,
+    pub auth_key: Option<String>,
+    /// Also used by `dirsync admin` when it isn't given.
+    pub admin_key: Option<String>,
+    pub max_connections: Option<usize>,
+    /// Seconds.
+    pub idle_timeout: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub bwlimit: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub bwlimit_per_conn: Option<u64>,
+    #[serde(deserialize_with = "schedule")]
+    pub bwlimit_schedule: Option<Schedule>,
+    #[serde(deserialize_with = "parsed")]
+    pub links: Option<LinkPolicy>,
+    pub releases: Option<String>,
+    pub metrics: Option<String>,
+    pub pid_file: Option<PathBuf>,
+    /// Seconds.
+    pub drain_timeout: Option<u64>,
+    pub history: Option<PathBuf>,
+    pub history_keep: Option<usize>,
+}
+
+/// The `[client]` table, named like the flags of `dirsync sync`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server: Option<String>,
+    pub dir: Option<String>,
+    pub auth_key: Option<String>,
+    pub jobs: Option<usize>,
+    #[serde(deserialize_with = "size")]
+    pub bwlimit: Option<u64>,
+    pub delete: Option<bool>,
+    #[serde(deserialize_with = "parsed")]
+    pub names: Option<NamePolicy>,
+    pub self_update: Option<bool>,
+    pub update_key: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub report: Option<ReportFormat>,
+    pub report_file: Option<PathBuf>,
+    pub progress: Option<bool>,
+    #[serde(deserialize_with = "parsed")]
+    pub reuse: Option<ReusePolicy>,
+    #[serde(deserialize_with = "parsed")]
+    pub local_changes: Option<LocalChangePolicy>,
+    pub backup_dir: Option<PathBuf>,
+    pub backup_suffix: Option<String>,
+}
+
+/// Values given as strings and parsed with `FromStr`, like the flags.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(D::Error::custom)
+}
+
+/// Sizes given as a number of bytes or a string like `512K`.
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(s) => parse_size(&s).map(Some).map_err(D::Error::custom),
+    }
+}
+
+fn schedule<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Schedule>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Schedule::parse(&s).map(Some).map_err(D::Error::custom)
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(text).map_err(|err| err.to_string())?;
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Self::parse(&text))
+            .map_err(|err| format!("config {}: {}", path.display(), err))
+    }
+
+    /// Loads `path`, or else the first file found in `default_paths`.
+    /// Returns the file used, if any; without one every setting is unset.
+    pub fn find(path: Option<&Path>) -> Result<(Self, Option<PathBuf>), String> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => default_paths().into_iter().find(|path| path.is_file()),
+        };
+        match path {
+            Some(path) => Ok((Self::load(&path)?, Some(path))),
+            None => Ok((Self::default(), None)),
+        }
+    }
+
+    /// Checks settings which depend on each other.
+    fn check(&self) -> Result<(), String> {
+        if self.client.self_update == Some(true) && self.client.update_key.is_none() {
+            return Err("client.self_update needs client.update_key".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Where a config file is looked for without `--config`, in order:
+/// `dirsync.toml` in the working directory, `dirsync/config.toml` in the user's
+/// config directory, then `/etc/dirsync/config.toml` on Unix.
+pub fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("dirsync.toml")];
+    let user_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    };
+    if let Some(dir) = user_dir {
+        paths.push(dir.join("dirsync").join("config.toml"));
+    }
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/dirsync/config.toml"));
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [log]
+            level = "debug"
+            format = "json"
+
+            [server]
+            listen = ":9100"
+            bwlimit = "2M"
+            bwlimit_per_conn = 1024
+            bwlimit_schedule = "08:00-18:00=1M"
+            links = "skip"
+
+            [client]
+            server = "backup:9100"
+            names = "fail"
+            progress = false
+            reuse = "hardlink"
+            local_changes = "abort"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.log.level, Some(Level::DEBUG));
+        assert_eq!(config.log.format, Some(LogFormat::Json));
+        assert_eq!(config.server.listen.as_deref(), Some(":9100"));
+        assert_eq!(config.server.bwlimit, Some(2 * 1024 * 1024));
+        assert_eq!(config.server.bwlimit_per_conn, Some(1024));
+        assert!(config.server.bwlimit_schedule.is_some());
+        assert_eq!(config.server.links, Some(LinkPolicy::Skip));
+        assert_eq!(config.server.dir, None);
+        assert_eq!(config.client.names, Some(NamePolicy::Fail));
+        assert_eq!(config.client.progress, Some(false));
+        assert_eq!(config.client.reuse, Some(ReusePolicy::Hardlink));
+        assert_eq!(config.client.local_changes, Some(LocalChangePolicy::Abort));
+
+        assert!(Config::parse("").is_ok());
+        // typos and settings dirsync doesn't have are errors
+        assert!(Config::parse("[client]\nservr = \"x\"").is_err());
+        assert!(Config::parse("[tls]\ncert = \"x.pem\"").is_err());
+        assert!(Config::parse("[server]\nbwlimit = \"fast\"").is_err());
+        assert!(Config::parse("[log]\nrotation = \"weekly\"").is_err());
+        assert!(Config::parse("[client]\nself_update = true").is_err());
+    }
+}
@@ -1,39 +1,189 @@
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::hash::Hasher;
 use std::io::{BufReader, Read};
-use std::os::windows::prelude::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::names::{
+    is_safe_link_target, raw_path, target_crosses_link, NameMapper, NamePolicy, RawName,
+};
+
+/// How the scanner treats symbolic links.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinkPolicy {
+    /// Record links as `SymlinkInfo` so clients recreate them.
+    #[default]
+    Preserve,
+    /// Scan the link target as if it were a regular file or directory.
+    Follow,
+    /// Leave links out of the tree.
+    Skip,
+}
+
+impl std::str::FromStr for LinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(LinkPolicy::Preserve),
+            "follow" => Ok(LinkPolicy::Follow),
+            "skip" => Ok(LinkPolicy::Skip),
+            _ => Err(format!("unknown link policy: {}", s)),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FileInfo {
     #[serde(skip)]
     pub path: std::path::PathBuf,
     pub path_hash: String,
-    pub name: String,
+    pub name: RawName,
     pub size: u64,
     pub last_modified: u64,
     pub hash: String,
+    /// Files sharing a group are hardlinks of each other.
+    pub hardlink_group: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SymlinkInfo {
+    #[serde(skip)]
+    pub path: std::path::PathBuf,
+    pub name: RawName,
+    /// Relative link target, with `/` separators.
+    pub target: RawName,
+    /// Whether the target is a directory, needed to create links on Windows.
+    pub is_dir: bool,
+}
+
+impl SymlinkInfo {
+    /// The link target as a local path, `None` if this platform can't represent it.
+    pub fn local_target(&self) -> Option<PathBuf> {
+        let target = self.target.to_os_string()?;
+        if cfg!(windows) {
+            Some(PathBuf::from(target.to_str()?.replace('/', "\\")))
+        } else {
+            Some(PathBuf::from(target))
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DirInfo {
+    /// The directory's path, on the server relative to the served directory,
+    /// on the client the local path set by `set_all_file_paths`.
+    #[serde(skip)]
     pub path: std::path::PathBuf,
+    /// The directory's own name, empty for the root.
+    pub name: RawName,
     pub files: Vec<FileInfo>,
     pub subdirs: Vec<DirInfo>,
+    pub symlinks: Vec<SymlinkInfo>,
+    /// Names of entries which are neither files, directories nor links
+    /// (fifos, sockets, devices). They are not synced.
+    pub others: Vec<String>,
+    /// Seconds since the unix epoch.
+    pub last_modified: u64,
+    /// Unix permission bits, `None` when the server doesn't have them.
+    pub mode: Option<u32>,
 }
 
 impl DirInfo {
-    pub fn set_all_file_paths(&mut self, root: &std::path::PathBuf) {
-        let dir_path = root.join(&self.path);
-        for file in &mut self.files {
-            file.path = dir_path.join(file.name.as_str())
+    /// Sets the local paths of every entry under `root`, mapping the server's
+    /// names to local names according to `policy`. Entries the policy skips are
+    /// removed from the tree. Returns a message for every changed or skipped name.
+    pub fn set_all_file_paths(
+        &mut self,
+        root: &Path,
+        policy: NamePolicy,
+    ) -> Result<Vec<String>, String> {
+        fn map_dir(
+            dir: &mut DirInfo,
+            server_path: &str,
+            depth: usize,
+            mapper: &mut NameMapper,
+        ) -> Result<(), String> {
+            // all names of a directory are mapped before descending, so they
+            // are checked against each other for collisions
+            mapper.enter_dir();
+            dir.subdirs.sort_by(|a, b| a.name.cmp(&b.name));
+            dir.files.sort_by(|a, b| a.name.cmp(&b.name));
+            dir.symlinks.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut subdirs = Vec::new();
+            for mut subdir in std::mem::take(&mut dir.subdirs) {
+                if let Some(name) = mapper.map(server_path, &subdir.name)? {
+                    subdir.path = dir.path.join(name);
+                    subdirs.push(subdir);
+                }
+            }
+            let mut files = Vec::new();
+            for mut file in std::mem::take(&mut dir.files) {
+                if let Some(name) = mapper.map(server_path, &file.name)? {
+                    file.path = dir.path.join(name);
+                    files.push(file);
+                }
+            }
+            let mut symlinks = Vec::new();
+            for mut symlink in std::mem::take(&mut dir.symlinks) {
+                if !is_safe_link_target(depth, &symlink.target) {
+                    return Err(format!(
+                        "{}/{}: unsafe link target {} from server",
+                        server_path, symlink.name, symlink.target
+                    ));
+                }
+                if let Some(name) = mapper.map(server_path, &symlink.name)? {
+                    symlink.path = dir.path.join(name);
+                    symlinks.push(symlink);
+                }
+            }
+            dir.files = files;
+            dir.symlinks = symlinks;
+            for subdir in &mut subdirs {
+                let subdir_path = format!("{}/{}", server_path, subdir.name);
+                map_dir(subdir, &subdir_path, depth + 1, mapper)?;
+            }
+            dir.subdirs = subdirs;
+            Ok(())
         }
-        for subdir in &mut self.subdirs {
-            subdir.set_all_file_paths(root);
+        fn collect_links<'a>(
+            dir: &'a DirInfo,
+            parent: &[&'a [u8]],
+            links: &mut Vec<(Vec<&'a [u8]>, &'a SymlinkInfo)>,
+        ) {
+            let mut path = parent.to_vec();
+            if !dir.name.0.is_empty() {
+                path.push(&dir.name.0);
+            }
+            for symlink in &dir.symlinks {
+                let mut link_path = path.clone();
+                link_path.push(&symlink.name.0);
+                links.push((link_path, symlink));
+            }
+            for subdir in &dir.subdirs {
+                collect_links(subdir, &path, links);
+            }
         }
+        let mut mapper = NameMapper::new(policy);
+        self.path = root.to_path_buf();
+        map_dir(self, "", 0, &mut mapper)?;
+        // each target was checked on its own, a chain of links can still
+        // leave the root
+        let mut links = Vec::new();
+        collect_links(self, &[], &mut links);
+        let link_paths: HashSet<_> = links.iter().map(|(path, _)| path.clone()).collect();
+        for (path, symlink) in &links {
+            if target_crosses_link(path, &symlink.target.0, &link_paths) {
+                return Err(format!(
+                    "/{}: link target {} from server goes through another link",
+                    String::from_utf8_lossy(&path.join(&b'/')),
+                    symlink.target
+                ));
+            }
+        }
+        Ok(mapper.warnings)
     }
 
     pub fn flat_hashes(&self) -> HashMap<&str, &FileInfo> {
@@ -50,6 +200,32 @@ impl DirInfo {
         result
     }
 
+    /// Every directory in the tree, parents first.
+    pub fn flat_dirs(&self) -> Vec<&DirInfo> {
+        fn flat_dir<'a>(dir: &'a DirInfo, list: &mut Vec<&'a DirInfo>) {
+            list.push(dir);
+            for subdir in &dir.subdirs {
+                flat_dir(subdir, list);
+            }
+        }
+        let mut result = Vec::new();
+        flat_dir(self, &mut result);
+        result
+    }
+
+    /// Every symlink in the tree.
+    pub fn flat_symlinks(&self) -> Vec<&SymlinkInfo> {
+        fn flat_dir<'a>(dir: &'a DirInfo, list: &mut Vec<&'a SymlinkInfo>) {
+            list.extend(&dir.symlinks);
+            for subdir in &dir.subdirs {
+                flat_dir(subdir, list);
+            }
+        }
+        let mut result = Vec::new();
+        flat_dir(self, &mut result);
+        result
+    }
+
     pub fn diff_with<'a>(&self, base: &'a DirInfo) -> Vec<&'a FileInfo> {
         let mut result = Vec::new();
         let base_hashes = base.flat_hashes();
@@ -72,56 +248,301 @@ impl FileInfo {
         }
         // assert!(p.exists() && p.is_file());
         let meta = fs::metadata(p)?;
-        let file_size = meta.file_size();
-        let last_write_time = meta.last_write_time();
-        let file = fs::File::open(&p)?;
+        let (file_size, last_write_time) = size_and_mtime(&meta);
+        let file = fs::File::open(p)?;
         let mut reader = BufReader::new(file);
 
-        let mut hasher: DefaultHasher = DefaultHasher::new();
+        let mut hasher = Sha256::new();
         let mut buffer = [0; 1024];
         loop {
-            let bytes_read = reader.read(&mut buffer).unwrap();
+            let bytes_read = reader.read(&mut buffer)?;
             if bytes_read == 0 {
                 break;
             }
-            hasher.write(&buffer[0..bytes_read])
+            hasher.update(&buffer[0..bytes_read])
         }
 
-        let result = hasher.finish();
-        let hash = format!("{:x}", result).to_string();
+        let hash = hex(&hasher.finalize());
         Ok(Self {
             path: p.to_path_buf(),
             // path_hash: get_hash(path.as_bytes()),
             path_hash: "".to_string(),
-            name: p.file_name().unwrap().to_str().unwrap().to_string(),
+            name: file_name(p),
             size: file_size,
             last_modified: last_write_time,
             hash,
+            hardlink_group: hardlink_group(&meta),
         })
     }
 }
 
-impl DirInfo {
-    pub fn new(dir: &std::path::PathBuf) -> Result<Self, std::io::Error> {
-        assert!(dir.is_dir());
+#[cfg(windows)]
+fn size_and_mtime(meta: &fs::Metadata) -> (u64, u64) {
+    use std::os::windows::prelude::MetadataExt;
+    (meta.file_size(), meta.last_write_time())
+}
+
+#[cfg(unix)]
+fn size_and_mtime(meta: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.size(), meta.mtime() as u64)
+}
+
+/// Modification time in seconds since the unix epoch, comparable across platforms.
+pub fn unix_mtime(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(unix)]
+pub fn unix_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn unix_mode(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Identifies the inode of a file with more than one link. Windows file
+/// indexes are not available on stable Rust, so hardlinks are not detected there.
+#[cfg(unix)]
+fn hardlink_group(meta: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    if meta.nlink() > 1 {
+        Some(get_hash(
+            format!("{}:{}", meta.dev(), meta.ino()).as_bytes(),
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn hardlink_group(_meta: &fs::Metadata) -> Option<String> {
+    None
+}
+
+/// Lexically resolves `.` and `..` in `path`, without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !result.pop() {
+                    result.push("..");
+                }
+            }
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
+fn file_name(path: &Path) -> RawName {
+    path.file_name()
+        .map(RawName::from_os_str)
+        .unwrap_or_default()
+}
+
+/// An entry left out of a scan, with the reason why.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SkippedEntry {
+    /// Path relative to the scanned directory.
+    pub path: String,
+    pub reason: String,
+}
+
+/// Entries a scan couldn't read or deliberately left out.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScanReport {
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// Reading a file is retried this many times before it is skipped, in case
+/// it is being written while it is scanned.
+const FILE_RETRIES: u32 = 2;
+
+/// Scans a directory tree, tracking the root and the directories being
+/// scanned so that followed links can't escape the root or loop. Entries
+/// which can't be read are skipped and recorded in `report`.
+struct Scanner {
+    /// The scanned directory as given, to make report paths relative.
+    base: PathBuf,
+    root: PathBuf,
+    policy: LinkPolicy,
+    /// Canonical paths of the directories currently being scanned.
+    ancestors: Vec<PathBuf>,
+    report: ScanReport,
+}
+
+impl Scanner {
+    fn skip(&mut self, path: &Path, reason: impl ToString) {
+        let path = path.strip_prefix(&self.base).unwrap_or(path);
+        self.report.skipped.push(SkippedEntry {
+            path: path.to_string_lossy().replace('\\', "/"),
+            reason: reason.to_string(),
+        });
+    }
+
+    fn scan_file(&mut self, path: &Path) -> Option<FileInfo> {
+        let mut attempt = 0;
+        loop {
+            match FileInfo::new(&path.to_path_buf()) {
+                Ok(file_info) => return Some(file_info),
+                Err(err)
+                    if attempt < FILE_RETRIES
+                        && !matches!(
+                            err.kind(),
+                            std::io::ErrorKind::NotFound
+                                | std::io::ErrorKind::PermissionDenied
+                                | std::io::ErrorKind::InvalidData
+                        ) =>
+                {
+                    attempt += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                Err(err) => {
+                    self.skip(path, err);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn scan_dir(&mut self, dir: &Path) -> Result<DirInfo, std::io::Error> {
+        let meta = fs::metadata(dir)?;
+        let entries = fs::read_dir(dir)?;
         let mut dir_info = DirInfo {
-            path: dir.clone(),
+            path: dir.to_path_buf(),
+            name: file_name(dir),
             files: Vec::new(),
             subdirs: Vec::new(),
+            symlinks: Vec::new(),
+            others: Vec::new(),
+            last_modified: unix_mtime(&meta),
+            mode: unix_mode(&meta),
         };
+        self.ancestors.push(fs::canonicalize(dir)?);
 
-        for entry in fs::read_dir(dir).unwrap() {
-            let entry = entry.unwrap();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    self.skip(dir, err);
+                    continue;
+                }
+            };
             let path = entry.path();
-            if path.is_dir() {
-                dir_info.subdirs.push(DirInfo::new(&dir.join(path))?);
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    self.skip(&path, err);
+                    continue;
+                }
+            };
+            if file_type.is_symlink() {
+                if let Err(err) = self.scan_symlink(&mut dir_info, &path) {
+                    self.skip(&path, err);
+                }
+            } else if file_type.is_dir() {
+                self.scan_subdir(&mut dir_info, &path);
+            } else if file_type.is_file() {
+                if let Some(file_info) = self.scan_file(&path) {
+                    dir_info.files.push(file_info);
+                }
             } else {
-                dir_info.files.push(FileInfo::new(&dir.join(path))?);
+                dir_info
+                    .others
+                    .push(entry.file_name().to_string_lossy().to_string());
             }
         }
+        self.ancestors.pop();
         Ok(dir_info)
     }
 
+    fn scan_subdir(&mut self, dir_info: &mut DirInfo, path: &Path) {
+        match self.scan_dir(path) {
+            Ok(subdir) => dir_info.subdirs.push(subdir),
+            Err(err) => self.skip(path, err),
+        }
+    }
+
+    fn scan_symlink(&mut self, dir_info: &mut DirInfo, path: &Path) -> Result<(), std::io::Error> {
+        let name = file_name(path);
+        match self.policy {
+            LinkPolicy::Skip => {}
+            LinkPolicy::Preserve => {
+                let target = fs::read_link(path)?;
+                let resolved =
+                    normalize(&std::path::absolute(path.parent().unwrap())?.join(&target));
+                // the text of the target may stay inside while a link it
+                // goes through leads out
+                let escapes =
+                    fs::canonicalize(path).is_ok_and(|real| !real.starts_with(&self.ancestors[0]));
+                if target.is_absolute() || !resolved.starts_with(&self.root) || escapes {
+                    self.skip(path, format!("link escapes root: {:?}", target));
+                    return Ok(());
+                }
+                dir_info.symlinks.push(SymlinkInfo {
+                    path: path.to_path_buf(),
+                    name,
+                    target: RawName(raw_path(&target)),
+                    is_dir: path.is_dir(),
+                });
+            }
+            LinkPolicy::Follow => {
+                let resolved = match fs::canonicalize(path) {
+                    Ok(resolved) => resolved,
+                    Err(_) => {
+                        self.skip(path, "dangling link");
+                        return Ok(());
+                    }
+                };
+                if !resolved.starts_with(&self.ancestors[0]) {
+                    self.skip(path, format!("link escapes root: {:?}", resolved));
+                } else if resolved.is_dir() {
+                    if self.ancestors.contains(&resolved) {
+                        self.skip(path, format!("link loop: {:?}", resolved));
+                    } else {
+                        self.scan_subdir(dir_info, path);
+                    }
+                } else if let Some(file_info) = self.scan_file(path) {
+                    dir_info.files.push(file_info);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DirInfo {
+    pub fn new(dir: &std::path::PathBuf) -> Result<Self, std::io::Error> {
+        Ok(Self::scan(dir, LinkPolicy::default())?.0)
+    }
+
+    /// Scans `dir` recursively, treating symbolic links according to `policy`.
+    /// Only a failure to read `dir` itself is an error, entries below it which
+    /// can't be read are left out and listed in the returned report.
+    pub fn scan(
+        dir: &std::path::PathBuf,
+        policy: LinkPolicy,
+    ) -> Result<(Self, ScanReport), std::io::Error> {
+        let mut scanner = Scanner {
+            base: dir.clone(),
+            root: normalize(&std::path::absolute(dir)?),
+            policy,
+            ancestors: Vec::new(),
+            report: ScanReport::default(),
+        };
+        let dir_info = scanner.scan_dir(dir)?;
+        Ok((dir_info, scanner.report))
+    }
+
     pub fn strip_root(&mut self) {
         fn strip_dir_info(dir: &mut DirInfo, root: &std::path::PathBuf) {
             dir.path = dir.path.strip_prefix(root).unwrap().to_path_buf();
@@ -130,18 +551,203 @@ impl DirInfo {
             }
             for file in &mut dir.files {
                 file.path = file.path.strip_prefix(root).unwrap().to_path_buf();
-                let file_path = file.path.to_str().unwrap().replace("\\", "/");
-                file.path_hash = get_hash(file_path.as_bytes());
+                file.path_hash = get_hash(&raw_path(&file.path));
             }
         }
         let root = self.path.clone();
         strip_dir_info(self, &root);
+        self.name = RawName::default();
     }
 }
 
+/// SHA-256 of `s` in hex, the same on every platform and release, as the
+/// hashes are stored and compared across machines.
 pub fn get_hash(s: &[u8]) -> String {
-    let mut hasher = DefaultHasher::new();
-    hasher.write(s);
-    let result = hasher.finish();
-    format!("{:x}", result).to_string()
+    hex(&Sha256::digest(s))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir_fixture(name: &str) -> DirInfo {
+        DirInfo {
+            path: PathBuf::new(),
+            name: name.into(),
+            files: Vec::new(),
+            subdirs: Vec::new(),
+            symlinks: Vec::new(),
+            others: Vec::new(),
+            last_modified: 0,
+            mode: None,
+        }
+    }
+
+    fn file_fixture(name: &str) -> FileInfo {
+        FileInfo {
+            path: PathBuf::new(),
+            path_hash: get_hash(name.as_bytes()),
+            name: name.into(),
+            size: 0,
+            last_modified: 0,
+            hash: String::new(),
+            hardlink_group: None,
+        }
+    }
+
+    fn symlink_fixture(name: &str, target: &str) -> SymlinkInfo {
+        SymlinkInfo {
+            path: PathBuf::new(),
+            name: name.into(),
+            target: target.into(),
+            is_dir: false,
+        }
+    }
+
+    #[test]
+    fn test_set_all_file_paths() {
+        let root = Path::new("/sync/root");
+        let mut sub = dir_fixture("sub");
+        sub.files.push(file_fixture("file"));
+        sub.symlinks.push(symlink_fixture("up", "../other"));
+        let mut base = dir_fixture("");
+        base.subdirs.push(sub);
+        base.set_all_file_paths(root, NamePolicy::Fail).unwrap();
+        assert_eq!(base.subdirs[0].files[0].path, root.join("sub/file"));
+        assert_eq!(base.subdirs[0].symlinks[0].path, root.join("sub/up"));
+    }
+
+    #[test]
+    fn test_set_all_file_paths_rejects_hostile_tree() {
+        let root = Path::new("/sync/root");
+        for name in ["..", "../../etc/passwd", "/etc/passwd", ".", ""] {
+            let mut base = dir_fixture("");
+            base.files.push(file_fixture(name));
+            assert!(
+                base.set_all_file_paths(root, NamePolicy::Escape).is_err(),
+                "{}",
+                name
+            );
+
+            let mut base = dir_fixture("");
+            base.subdirs.push(dir_fixture(name));
+            assert!(
+                base.set_all_file_paths(root, NamePolicy::Skip).is_err(),
+                "{}",
+                name
+            );
+        }
+
+        for target in ["../etc", "/etc", "a/../../etc"] {
+            let mut base = dir_fixture("");
+            base.symlinks.push(symlink_fixture("link", target));
+            assert!(
+                base.set_all_file_paths(root, NamePolicy::Escape).is_err(),
+                "{}",
+                target
+            );
+        }
+        let mut sub = dir_fixture("sub");
+        sub.symlinks.push(symlink_fixture("link", "../../etc"));
+        let mut base = dir_fixture("");
+        base.subdirs.push(sub);
+        assert!(base.set_all_file_paths(root, NamePolicy::Escape).is_err());
+
+        // each target stays inside on its own, but x resolves to d/s, which
+        // is the root, then to its parent
+        let chain = |target: &str| {
+            let mut d = dir_fixture("d");
+            d.symlinks.push(symlink_fixture("s", ".."));
+            let mut base = dir_fixture("");
+            base.subdirs.push(d);
+            base.symlinks.push(symlink_fixture("x", target));
+            base.set_all_file_paths(root, NamePolicy::Escape)
+        };
+        assert!(chain("d/s/..").is_err());
+        assert!(chain("d/./s/../d").is_err());
+        // links to links are followed to their end, which was checked
+        assert!(chain("d/s").is_ok());
+        assert!(chain("d/../d/s").is_ok());
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(
+            get_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("a.txt");
+        fs::write(&path, vec![7; 3000]).unwrap();
+        assert_eq!(FileInfo::new(&path).unwrap().hash, get_hash(&[7; 3000]));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("/a/b/../c/./d")), Path::new("/a/c/d"));
+        assert_eq!(normalize(Path::new("a/../../b")), Path::new("../b"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_links() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("file"), "content").unwrap();
+        fs::hard_link(root.join("file"), root.join("sub/hard")).unwrap();
+        std::os::unix::fs::symlink("../file", root.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink("../../outside", root.join("sub/escape")).unwrap();
+        std::os::unix::fs::symlink("sub/loop/..", root.join("chain")).unwrap();
+
+        let preserved = DirInfo::scan(&root, LinkPolicy::Preserve).unwrap().0;
+        assert!(preserved.symlinks.is_empty());
+        let mut links: Vec<_> = preserved.subdirs[0]
+            .symlinks
+            .iter()
+            .map(|l| l.name.to_string())
+            .collect();
+        links.sort();
+        assert_eq!(links, vec!["link", "loop"]);
+        let groups: Vec<_> = preserved
+            .flat_hashes()
+            .values()
+            .map(|f| f.hardlink_group.clone())
+            .collect();
+        assert!(groups.iter().all(|g| g.is_some() && *g == groups[0]));
+
+        let followed = DirInfo::scan(&root, LinkPolicy::Follow).unwrap().0;
+        let sub = &followed.subdirs[0];
+        assert!(sub.symlinks.is_empty() && sub.subdirs.is_empty());
+        let mut names: Vec<_> = sub.files.iter().map(|f| f.name.to_string()).collect();
+        names.sort();
+        assert_eq!(names, vec!["hard", "link"]);
+
+        let skipped = DirInfo::scan(&root, LinkPolicy::Skip).unwrap().0;
+        assert_eq!(skipped.subdirs[0].files.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_report() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::write(root.join("good"), "content").unwrap();
+        fs::write(root.join(OsStr::from_bytes(b"bad\xff")), "content").unwrap();
+        std::os::unix::fs::symlink("missing", root.join(OsStr::from_bytes(b"link\xff"))).unwrap();
+
+        // names which aren't UTF-8 are kept, the dangling link is reported
+        let (dir_info, report) = DirInfo::scan(&root, LinkPolicy::Follow).unwrap();
+        assert_eq!(dir_info.files.len(), 2);
+        assert!(dir_info.files.iter().any(|f| f.name.0 == b"bad\xff"));
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].path, "link\u{fffd}");
+    }
 }
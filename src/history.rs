@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::common::{compress_bytes, tmp_path};
+use crate::fileinfo::{get_hash, DirInfo, FileInfo};
+
+/// A version of the served directory kept in the history.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub version: u64,
+    /// Local time the version was recorded, in RFC 3339.
+    pub created_at: String,
+    pub files: usize,
+    /// Total size of the files.
+    pub size: u64,
+}
+
+/// A recorded version with its file list as served in `DirInfo`.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    info: VersionInfo,
+    dir_info: DirInfo,
+}
+
+/// Versions of the served directory, one for every scan that changed it,
+/// each kept with the content of its files so it can still be served after
+/// the directory moved on.
+///
+/// The history directory holds a manifest per version in `versions/<N>.json`
+/// and the compressed content of the files in `blobs/<hash>`, shared by all
+/// versions having that content.
+pub struct History {
+    dir: PathBuf,
+    versions: Vec<VersionInfo>,
+    /// File list of the latest version, to tell whether a scan changed anything.
+    latest: Option<String>,
+}
+
+impl History {
+    /// Opens the history in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.join("versions"))?;
+        std::fs::create_dir_all(dir.join("blobs"))?;
+        let mut history = Self {
+            dir: dir.to_path_buf(),
+            versions: Vec::new(),
+            latest: None,
+        };
+        for entry in std::fs::read_dir(dir.join("versions"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match history.read_manifest(&path) {
+                    Ok(manifest) => history.versions.push(manifest.info),
+                    Err(err) => warn!(path = %path.display(), "version skipped: {}", err),
+                }
+            }
+        }
+        history.versions.sort_by_key(|v| v.version);
+        if let Some(latest) = history.versions.last() {
+            let dir_info = history.dir_info(latest.version)?;
+            history.latest = Some(serde_json::to_string(&dir_info).unwrap());
+        }
+        info!(dir = %dir.display(), versions = history.versions.len(), "history opened");
+        Ok(history)
+    }
+
+    /// The recorded versions, oldest first.
+    pub fn versions(&self) -> &[VersionInfo] {
+        &self.versions
+    }
+
+    fn manifest_path(&self, version: u64) -> PathBuf {
+        self.dir.join("versions").join(format!("{}.json", version))
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("blobs").join(hash)
+    }
+
+    fn read_manifest(&self, path: &Path) -> std::io::Result<Manifest> {
+        let text = std::fs::read_to_string(path)?;
+        serde_json::from_str(&text)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    /// The file list of `version`.
+    pub fn dir_info(&self, version: u64) -> std::io::Result<DirInfo> {
+        Ok(self.read_manifest(&self.manifest_path(version))?.dir_info)
+    }
+
+    /// The compressed content with `hash`, as sent in `Response::File`.
+    /// `hash` comes from clients, anything but a SHA-256 hash is not found.
+    pub fn blob(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        let is_hash =
+            hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        if !is_hash {
+            return Err(std::io::ErrorKind::NotFound.into());
+        }
+        std::fs::read(self.blob_path(hash))
+    }
+
+    /// Records `dir_info` as a new version unless it lists the same as the
+    /// latest one. `files` are the files of `dir_info` relative to
+    /// `target_dir`, whose content is stored first. Only the `keep` latest
+    /// versions are kept. Returns the new version.
+    pub fn record<'a>(
+        &mut self,
+        target_dir: &Path,
+        dir_info: &DirInfo,
+        files: impl IntoIterator<Item = &'a FileInfo>,
+        keep: usize,
+    ) -> std::io::Result<Option<u64>> {
+        let listed = serde_json::to_string(dir_info).unwrap();
+        if self.latest.as_ref() == Some(&listed) {
+            return Ok(None);
+        }
+        let mut count = 0;
+        let mut size = 0;
+        for file_info in files {
+            count += 1;
+            size += file_info.size;
+            self.store_blob(&target_dir.join(&file_info.path), &file_info.hash)?;
+        }
+        let info = VersionInfo {
+            version: self.versions.last().map_or(1, |v| v.version + 1),
+            created_at: Local::now().to_rfc3339(),
+            files: count,
+            size,
+        };
+        let manifest = Manifest {
+            info: info.clone(),
+            dir_info: dir_info.clone(),
+        };
+        let path = self.manifest_path(info.version);
+        let tmp_path = tmp_path(&path);
+        std::fs::write(&tmp_path, serde_json::to_string(&manifest).unwrap())?;
+        std::fs::rename(&tmp_path, &path)?;
+        let version = info.version;
+        self.versions.push(info);
+        self.latest = Some(listed);
+        self.prune(keep.max(1))?;
+        Ok(Some(version))
+    }
+
+    /// Stores the content of `path` unless content with `hash` is stored.
+    fn store_blob(&self, path: &Path, hash: &str) -> std::io::Result<()> {
+        let blob_path = self.blob_path(hash);
+        if blob_path.exists() {
+            return Ok(());
+        }
+        let content = std::fs::read(path)?;
+        if get_hash(&content) != hash {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} changed since the scan", path.display()),
+            ));
+        }
+        let tmp_path = tmp_path(&blob_path);
+        std::fs::write(&tmp_path, compress_bytes(&content))?;
+        std::fs::rename(&tmp_path, &blob_path)
+    }
+
+    /// Drops all but the `keep` latest versions, and the content only they had.
+    fn prune(&mut self, keep: usize) -> std::io::Result<()> {
+        if self.versions.len() <= keep {
+            return Ok(());
+        }
+        let dropped: Vec<_> = self.versions.drain(..self.versions.len() - keep).collect();
+        for info in &dropped {
+            std::fs::remove_file(self.manifest_path(info.version))?;
+            debug!(version = info.version, "version dropped");
+        }
+        let mut hashes = HashSet::new();
+        for info in &self.versions {
+            let dir_info = self.dir_info(info.version)?;
+            hashes.extend(dir_info.flat_hashes().values().map(|f| f.hash.clone()));
+        }
+        for entry in std::fs::read_dir(self.dir.join("blobs"))? {
+            let entry = entry?;
+            if !hashes.contains(entry.file_name().to_string_lossy().as_ref()) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The latest of `versions` recorded at or before `time`.
+pub fn version_at(versions: &[VersionInfo], time: DateTime<FixedOffset>) -> Option<&VersionInfo> {
+    versions
+        .iter()
+        .filter(|v| {
+            DateTime::parse_from_rfc3339(&v.created_at).is_ok_and(|created| created <= time)
+        })
+        .max_by_key(|v| v.version)
+}
+
+/// Parses a time in RFC 3339, or a local `YYYY-MM-DD HH:MM[:SS]` or
+/// `YYYY-MM-DD`, which means midnight.
+pub fn parse_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time);
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("invalid time: {}", s))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.fixed_offset())
+        .ok_or_else(|| format!("time doesn't exist locally: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(dir: &Path) -> DirInfo {
+        let mut dir_info = DirInfo::new(&dir.to_path_buf()).unwrap();
+        dir_info.strip_root();
+        dir_info
+    }
+
+    #[test]
+    fn test_record() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let served = root.join("served");
+        std::fs::create_dir_all(&served).unwrap();
+        std::fs::write(served.join("a.txt"), "one").unwrap();
+        let mut history = History::open(&root.join("history")).unwrap();
+
+        let dir_info = scan(&served);
+        let files = dir_info.flat_hashes();
+        assert_eq!(
+            history
+                .record(&served, &dir_info, files.values().copied(), 2)
+                .unwrap(),
+            Some(1)
+        );
+        // nothing changed
+        assert_eq!(
+            history
+                .record(&served, &dir_info, files.values().copied(), 2)
+                .unwrap(),
+            None
+        );
+        let first_hash = files.values().next().unwrap().hash.clone();
+        assert_eq!(history.blob(&first_hash).unwrap(), compress_bytes(b"one"));
+
+        for (i, content) in ["two", "three"].iter().enumerate() {
+            std::fs::write(served.join("a.txt"), content).unwrap();
+            let dir_info = scan(&served);
+            let files = dir_info.flat_hashes();
+            let version = history
+                .record(&served, &dir_info, files.values().copied(), 2)
+                .unwrap();
+            assert_eq!(version, Some(i as u64 + 2));
+        }
+        // the first version and its content are dropped
+        let versions: Vec<_> = history.versions().iter().map(|v| v.version).collect();
+        assert_eq!(versions, vec![2, 3]);
+        assert!(history.blob(&first_hash).is_err());
+        assert_eq!(history.dir_info(2).unwrap().files.len(), 1);
+
+        let reopened = History::open(&root.join("history")).unwrap();
+        assert_eq!(reopened.versions(), history.versions());
+
+        // only hashes name blobs
+        std::fs::write(root.join("history/manifest"), "secret").unwrap();
+        for hash in ["../manifest", "/etc/passwd", &first_hash.to_uppercase()] {
+            let err = history.blob(hash).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        }
+    }
+
+    #[test]
+    fn test_version_at() {
+        let version = |version, created_at: &str| VersionInfo {
+            version,
+            created_at: created_at.to_string(),
+            files: 0,
+            size: 0,
+        };
+        let versions = [
+            version(1, "2026-03-01T10:00:00+00:00"),
+            version(2, "2026-03-02T10:00:00+00:00"),
+        ];
+        let at = |s| version_at(&versions, parse_time(s).unwrap()).map(|v| v.version);
+        assert_eq!(at("2026-03-01T09:00:00+00:00"), None);
+        assert_eq!(at("2026-03-01T10:00:00+00:00"), Some(1));
+        assert_eq!(at("2026-03-02T12:00:00+02:00"), Some(2));
+        assert_eq!(at("2026-04-01T00:00:00Z"), Some(2));
+
+        assert!(parse_time("2026-03-01 10:00").is_ok());
+        assert!(parse_time("2026-03-01").is_ok());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -1,4 +1,19 @@
+pub mod admin;
+pub mod backup;
+pub mod cache;
 pub mod client;
 pub mod common;
+pub mod config;
 pub mod fileinfo;
+pub mod history;
+pub mod logging;
+pub mod metrics;
+pub mod names;
+pub mod pool;
+pub mod progress;
+pub mod ratelimit;
+pub mod release;
+pub mod report;
 pub mod server;
+pub mod service;
+pub mod state;
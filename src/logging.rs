@@ -0,0 +1,136 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use tracing::Level;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// How log events are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human readable line per event.
+    #[default]
+    Text,
+    /// One JSON object per event, with span fields such as the peer address.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// When a log file is rotated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl std::str::FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(LogRotation::Never),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            _ => Err(format!("unknown log rotation: {}", s)),
+        }
+    }
+}
+
+/// Settings of `init`.
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    pub level: Level,
+    pub format: LogFormat,
+    /// Events go to this file instead of stderr. Rotated files get the date
+    /// appended to this name.
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            level: Level::INFO,
+            format: LogFormat::default(),
+            file: None,
+            rotation: LogRotation::default(),
+        }
+    }
+}
+
+/// The level for a `--debug` count: info, then debug, then trace.
+pub fn level_for(debug: u8) -> Level {
+    match debug {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
+/// Installs the global subscriber.
+pub fn init(options: &LogOptions) -> Result<(), String> {
+    let (writer, ansi) = match &options.file {
+        Some(path) => {
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(std::path::Path::new("."));
+            let name = path
+                .file_name()
+                .ok_or_else(|| format!("invalid log file: {}", path.display()))?;
+            let rotation = match options.rotation {
+                LogRotation::Never => Rotation::NEVER,
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+            };
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(name.to_string_lossy())
+                .build(dir)
+                .map_err(|err| format!("log file {}: {}", path.display(), err))?;
+            (BoxMakeWriter::new(appender), false)
+        }
+        None => (
+            BoxMakeWriter::new(std::io::stderr),
+            std::io::stderr().is_terminal(),
+        ),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(options.level)
+        .with_target(false)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match options.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert_eq!("daily".parse(), Ok(LogRotation::Daily));
+        assert!("weekly".parse::<LogRotation>().is_err());
+        assert_eq!(level_for(0), Level::INFO);
+        assert_eq!(level_for(5), Level::TRACE);
+    }
+}
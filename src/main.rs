@@ -1,15 +1,47 @@
-use dirsync::client::client_main;
-use dirsync::server::server_main;
+use dirsync::backup;
+use dirsync::client::{
+    admin, client_main, list_releases, list_versions, ClientOptions, LocalChangePolicy, ReusePolicy,
+};
+use dirsync::common::{human_duration, human_size, parse_size, Request, Response};
+use dirsync::config::{self, Config, ServerConfig};
+use dirsync::fileinfo::LinkPolicy;
+use dirsync::history;
+use dirsync::logging::{self, LogFormat, LogOptions, LogRotation};
+use dirsync::names::NamePolicy;
+use dirsync::ratelimit::Schedule;
+use dirsync::release::{self, current_target, VERSION};
+use dirsync::report::ReportFormat;
+use dirsync::server::{server_main, ServerOptions};
+use dirsync::service;
 
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, FixedOffset};
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// Turn debugging information on
+    /// Turn debugging information on, twice for tracing
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
 
+    /// Config file [default: the first of dirsync.toml, ~/.config/dirsync/config.toml, /etc/dirsync/config.toml]
+    #[arg(short, long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Log format: text or json [default: text]
+    #[arg(long, global = true, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Write logs to this file instead of stderr
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Start a new log file: never, hourly or daily [default: never]
+    #[arg(long, global = true, value_name = "WHEN")]
+    log_rotation: Option<LogRotation>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -18,34 +50,329 @@ pub struct Cli {
 pub enum Commands {
     Sync {
         #[arg(short, long, value_name = "SERVER")]
-        server: String,
+        server: Option<String>,
 
-        #[arg(short, long, default_value_t = String::from("."), value_name = "DIR")]
-        dir: String,
+        /// [default: .]
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<String>,
 
-        #[arg(long, default_value_t = String::from("friday"), value_name = "AUTH_KEY")]
-        auth_key: String,
+        /// [default: friday]
+        #[arg(long, value_name = "AUTH_KEY")]
+        auth_key: Option<String>,
 
         #[arg(long, default_value_t = false, value_name = "DRY RUN")]
         dry_run: bool,
 
         #[arg(short, long, default_value_t = false, value_name = "VERBOSE")]
         verbose: bool,
+
+        /// Number of parallel connections used to download files [default: 1]
+        #[arg(short, long, value_name = "JOBS")]
+        jobs: Option<usize>,
+
+        /// Download bandwidth limit in bytes per second, e.g. 512K or 2M (0 for unlimited) [default: 0]
+        #[arg(long, value_name = "RATE", value_parser = parse_size)]
+        bwlimit: Option<u64>,
+
+        /// Delete local files and directories which are not on the server
+        #[arg(long, default_value_t = false, overrides_with = "no_delete")]
+        delete: bool,
+
+        /// Keep local files which are not on the server, even with `delete` in the config file
+        #[arg(long, overrides_with = "delete")]
+        no_delete: bool,
+
+        /// What to do with server names this platform can't use: escape, skip or fail [default: escape]
+        #[arg(long, value_name = "POLICY")]
+        names: Option<NamePolicy>,
+
+        /// Update this binary to the server's signed release for this platform
+        #[arg(long, default_value_t = false, overrides_with = "no_self_update")]
+        self_update: bool,
+
+        /// Don't update this binary, even with `self_update` in the config file
+        #[arg(long, overrides_with = "self_update")]
+        no_self_update: bool,
+
+        /// Public key releases must be signed with, in hex or as a file holding the hex
+        #[arg(long, value_name = "KEY")]
+        update_key: Option<String>,
+
+        /// Release version to update to, allows downgrades [default: latest]
+        #[arg(long, value_name = "VERSION")]
+        update_version: Option<String>,
+
+        /// Print a report of what was synced in this format: json
+        #[arg(long, value_name = "FORMAT")]
+        report: Option<ReportFormat>,
+
+        /// Write the report to this file instead of stdout
+        #[arg(long, value_name = "FILE")]
+        report_file: Option<PathBuf>,
+
+        /// Don't show the download progress
+        #[arg(long)]
+        no_progress: bool,
+
+        /// How files whose content is already in DIR are created instead of downloaded: copy, hardlink or off [default: copy]
+        #[arg(long, value_name = "POLICY")]
+        reuse: Option<ReusePolicy>,
+
+        /// What to do with files changed locally since the last sync: overwrite, skip or abort [default: overwrite]
+        #[arg(long, value_name = "POLICY")]
+        local_changes: Option<LocalChangePolicy>,
+
+        /// Sync this version of the server's history instead of its current files
+        #[arg(long, value_name = "N", conflicts_with = "at")]
+        version: Option<u64>,
+
+        /// Sync the version of the server's history current at this time, e.g. "2026-03-01 14:00" or RFC 3339
+        #[arg(long, value_name = "TIME", value_parser = history::parse_time)]
+        at: Option<DateTime<FixedOffset>>,
+
+        /// Move files the sync replaces or deletes to this directory, below a directory named after the time of the sync
+        #[arg(long, value_name = "DIR")]
+        backup_dir: Option<PathBuf>,
+
+        /// Keep files the sync replaces or deletes, next to them with the time of the sync and this suffix, or with --backup-dir appended to their names
+        #[arg(long, value_name = "SUFFIX")]
+        backup_suffix: Option<String>,
+    },
+    /// Undo the last sync of a directory which kept backups
+    Restore {
+        /// [default: .]
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<String>,
+    },
+    /// List the versions in a server's history
+    Versions {
+        #[arg(short, long, value_name = "SERVER")]
+        server: Option<String>,
+
+        /// [default: friday]
+        #[arg(long, value_name = "AUTH_KEY")]
+        auth_key: Option<String>,
+    },
+    /// List the client releases a server offers
+    Releases {
+        #[arg(short, long, value_name = "SERVER")]
+        server: Option<String>,
+
+        /// [default: friday]
+        #[arg(long, value_name = "AUTH_KEY")]
+        auth_key: Option<String>,
+    },
+    Server(ServerArgs),
+    /// Generate a key pair for signing releases
+    Keygen {
+        /// File the secret key is written to
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Sign a binary as a release, writing <BINARY>.sig
+    Sign {
+        /// Secret key file written by keygen
+        #[arg(short, long, value_name = "FILE")]
+        key: PathBuf,
+
+        /// Platform the binary runs on, defaults to this one's
+        #[arg(long, default_value_t = current_target(), value_name = "TARGET")]
+        target: String,
+
+        /// Version of the binary, defaults to this one's
+        #[arg(long, default_value_t = VERSION.to_string(), value_name = "VERSION")]
+        release_version: String,
+
+        binary: PathBuf,
     },
-    Server {
-        #[arg(short, long, default_value_t = String::from(":9022"), value_name = "SERVER")]
-        listen: String,
+    /// Inspect and control a running server, with its admin key
+    Admin {
+        #[arg(short, long, value_name = "SERVER")]
+        server: Option<String>,
 
-        #[arg(short, long, default_value_t = String::from("."), value_name = "DIR")]
-        dir: String,
+        /// The server's admin key
+        #[arg(long, value_name = "KEY")]
+        admin_key: Option<String>,
 
-        #[arg(long, default_value_t = String::from("friday"), value_name = "AUTH_KEY")]
-        auth_key: String,
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+    /// Work with the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
 }
 
+#[derive(Args, Clone)]
+pub struct ServerArgs {
+    /// [default: :9022]
+    #[arg(short, long, value_name = "SERVER")]
+    listen: Option<String>,
+
+    /// [default: .]
+    #[arg(short, long, value_name = "DIR")]
+    dir: Option<String>,
+
+    /// [default: friday]
+    #[arg(long, value_name = "AUTH_KEY")]
+    auth_key: Option<String>,
+
+    /// Key for `dirsync admin`, admin requests are refused without one
+    #[arg(long, value_name = "KEY")]
+    admin_key: Option<String>,
+
+    /// Maximum number of concurrent client connections [default: 64]
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+
+    /// Close connections idle for this many seconds [default: 300]
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Upload bandwidth limit of all connections together, e.g. 512K or 2M (0 for unlimited) [default: 0]
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    bwlimit: Option<u64>,
+
+    /// Upload bandwidth limit of each connection (0 for unlimited) [default: 0]
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    bwlimit_per_conn: Option<u64>,
+
+    /// Local time windows overriding --bwlimit, e.g. "08:00-18:00=1M,18:00-20:00=10M"
+    #[arg(long, value_name = "SCHEDULE", value_parser = Schedule::parse)]
+    bwlimit_schedule: Option<Schedule>,
+
+    /// How symbolic links are served: preserve, follow or skip [default: preserve]
+    #[arg(long, value_name = "POLICY")]
+    links: Option<LinkPolicy>,
+
+    /// Directory of client releases laid out as <VERSION>/<TARGET>/<BINARY>
+    #[arg(long, value_name = "DIR")]
+    releases: Option<String>,
+
+    /// Serve Prometheus metrics over HTTP at this address, e.g. 127.0.0.1:9023
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<String>,
+
+    /// Write the process id to this file while running
+    #[arg(long, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Seconds in-flight transfers may take to finish on shutdown [default: 60]
+    #[arg(long, value_name = "SECS")]
+    drain_timeout: Option<u64>,
+
+    /// Keep a version of the served directory in this directory for every scan which changed it
+    #[arg(long, value_name = "DIR")]
+    history: Option<PathBuf>,
+
+    /// Number of versions kept in the history [default: 10]
+    #[arg(long, value_name = "N")]
+    history_keep: Option<usize>,
+
+    /// Print a systemd unit running the server with these options, and exit
+    #[arg(long)]
+    print_unit: bool,
+}
+
+impl ServerArgs {
+    /// The settings from these flags, falling back to `file`.
+    fn options(&self, file: ServerConfig) -> ServerOptions {
+        let args = self.clone();
+        let defaults = ServerOptions::default();
+        ServerOptions {
+            addr: args.listen.or(file.listen).unwrap_or(defaults.addr),
+            target_dir: args.dir.or(file.dir).unwrap_or(defaults.target_dir),
+            auth_key: args.auth_key.or(file.auth_key).unwrap_or(defaults.auth_key),
+            admin_key: args.admin_key.or(file.admin_key),
+            max_connections: args
+                .max_connections
+                .or(file.max_connections)
+                .unwrap_or(defaults.max_connections),
+            idle_timeout: args
+                .idle_timeout
+                .or(file.idle_timeout)
+                .map(std::time::Duration::from_secs)
+                .unwrap_or(defaults.idle_timeout),
+            bwlimit: args.bwlimit.or(file.bwlimit).unwrap_or(defaults.bwlimit),
+            bwlimit_per_conn: args
+                .bwlimit_per_conn
+                .or(file.bwlimit_per_conn)
+                .unwrap_or(defaults.bwlimit_per_conn),
+            bwlimit_schedule: args
+                .bwlimit_schedule
+                .or(file.bwlimit_schedule)
+                .unwrap_or(defaults.bwlimit_schedule),
+            links: args.links.or(file.links).unwrap_or(defaults.links),
+            releases_dir: args.releases.or(file.releases),
+            metrics_addr: args.metrics.or(file.metrics),
+            pid_file: args.pid_file.or(file.pid_file),
+            drain_timeout: args
+                .drain_timeout
+                .or(file.drain_timeout)
+                .map(std::time::Duration::from_secs)
+                .unwrap_or(defaults.drain_timeout),
+            history_dir: args.history.or(file.history),
+            history_keep: args
+                .history_keep
+                .or(file.history_keep)
+                .unwrap_or(defaults.history_keep),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Show the served generation, scan status and file cache
+    Status,
+    /// List connected clients and what they are downloading
+    Clients,
+    /// Rescan the served directory now
+    Rescan,
+    /// Empty the file cache
+    FlushCache,
+    /// Reload the server's config file
+    Reload,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the config file and show where it was found
+    Check,
+}
+
+/// The setting of a switch with an `--x` and a `--no-x` flag, if either was given.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Prints `err` and exits with status 2.
+fn exit_with(err: impl std::fmt::Display) -> ! {
+    eprintln!("error: {}", err);
+    std::process::exit(2);
+}
+
 fn main() {
     let cli = Cli::parse();
+    let (config, config_path) =
+        Config::find(cli.config.as_deref()).unwrap_or_else(|err| exit_with(err));
+    // -v of sync shows what -d shows
+    let verbose = matches!(cli.command, Some(Commands::Sync { verbose: true, .. }));
+    let debug = cli.debug.max(verbose as u8);
+    logging::init(&LogOptions {
+        level: match debug {
+            0 => config.log.level.unwrap_or(logging::level_for(0)),
+            _ => logging::level_for(debug),
+        },
+        format: cli.log_format.or(config.log.format).unwrap_or_default(),
+        file: cli.log_file.or(config.log.file),
+        rotation: cli.log_rotation.or(config.log.rotation).unwrap_or_default(),
+    })
+    .unwrap_or_else(|err| exit_with(err));
 
     match cli.command {
         Some(Commands::Sync {
@@ -53,15 +380,257 @@ fn main() {
             dir,
             auth_key,
             dry_run,
-            verbose,
-        }) => client_main(&server, &dir, &auth_key, dry_run, verbose).unwrap(),
-        Some(Commands::Server {
-            listen,
-            dir,
-            auth_key,
+            verbose: _,
+            jobs,
+            bwlimit,
+            delete,
+            no_delete,
+            names,
+            self_update,
+            no_self_update,
+            update_key,
+            update_version,
+            report,
+            report_file,
+            no_progress,
+            reuse,
+            local_changes,
+            version,
+            at,
+            backup_dir,
+            backup_suffix,
+        }) => {
+            let file = config.client;
+            let options = ClientOptions {
+                server: server
+                    .or(file.server)
+                    .unwrap_or_else(|| exit_with("no server given, use -s or client.server")),
+                dir: dir.or(file.dir).unwrap_or_else(|| ".".to_string()),
+                auth_key: auth_key
+                    .or(file.auth_key)
+                    .unwrap_or_else(|| "friday".to_string()),
+                dry_run,
+                jobs: jobs.or(file.jobs).unwrap_or(1),
+                bwlimit: bwlimit.or(file.bwlimit).unwrap_or(0),
+                delete: switch(delete, no_delete).or(file.delete).unwrap_or(false),
+                names: names.or(file.names).unwrap_or_default(),
+                self_update: switch(self_update, no_self_update)
+                    .or(file.self_update)
+                    .unwrap_or(false),
+                update_key: update_key.or(file.update_key),
+                update_version,
+                report: report.or(file.report),
+                report_file: report_file.or(file.report_file),
+                progress: !no_progress && file.progress.unwrap_or(true),
+                reuse: reuse.or(file.reuse).unwrap_or_default(),
+                local_changes: local_changes.or(file.local_changes).unwrap_or_default(),
+                version,
+                at,
+                backup_dir: backup_dir.or(file.backup_dir),
+                backup_suffix: backup_suffix.or(file.backup_suffix),
+            };
+            if options.self_update && options.update_key.is_none() {
+                exit_with("--self-update needs --update-key");
+            }
+            if options.update_version.is_some() && !options.self_update {
+                exit_with("--update-version needs --self-update");
+            }
+            client_main(&options).unwrap_or_else(|err| exit_with(err));
+        }
+        Some(Commands::Server(args)) => {
+            let print_unit = args.print_unit;
+            let options = args.options(config.server);
+            // read the file found now again, or look for one if there was none
+            let path = cli.config.or(config_path);
+            let reload = move || {
+                Config::find(path.as_deref()).map(|(config, _)| args.options(config.server))
+            };
+            if print_unit {
+                let args: Vec<String> = std::env::args()
+                    .skip(1)
+                    .filter(|arg| arg != "--print-unit")
+                    .collect();
+                let exe = std::env::current_exe().unwrap();
+                let dir = std::env::current_dir().unwrap();
+                print!(
+                    "{}",
+                    service::unit(&exe, &args, &dir, options.drain_timeout)
+                );
+                return;
+            }
+            if let Err(err) = server_main(&options, Box::new(reload)) {
+                tracing::error!("server failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Releases { server, auth_key }) => {
+            let server = server
+                .or(config.client.server)
+                .unwrap_or_else(|| exit_with("no server given, use -s or client.server"));
+            let auth_key = auth_key
+                .or(config.client.auth_key)
+                .unwrap_or_else(|| "friday".to_string());
+            let current = current_target();
+            for release in list_releases(&server, &auth_key).unwrap_or_else(|err| exit_with(err)) {
+                println!(
+                    "{}\t{}\t{}\t{}{}",
+                    release.version,
+                    release.target,
+                    human_size(release.size),
+                    if release.signature.is_empty() {
+                        "unsigned"
+                    } else {
+                        "signed"
+                    },
+                    if release.target == current && release.version == VERSION {
+                        "\t(this binary's version)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        Some(Commands::Versions { server, auth_key }) => {
+            let server = server
+                .or(config.client.server)
+                .unwrap_or_else(|| exit_with("no server given, use -s or client.server"));
+            let auth_key = auth_key
+                .or(config.client.auth_key)
+                .unwrap_or_else(|| "friday".to_string());
+            let versions = list_versions(&server, &auth_key).unwrap_or_else(|err| exit_with(err));
+            if versions.is_empty() {
+                println!("no versions, the server keeps no history");
+            }
+            for version in versions {
+                println!(
+                    "{}\t{}\t{} files\t{}",
+                    version.version,
+                    version.created_at,
+                    version.files,
+                    human_size(version.size)
+                );
+            }
+        }
+        Some(Commands::Restore { dir }) => {
+            let dir = dir.or(config.client.dir).unwrap_or_else(|| ".".to_string());
+            match backup::restore(Path::new(&dir)) {
+                Ok(Some(restored)) => {
+                    println!(
+                        "restored the sync of {}: {} changes undone",
+                        restored.started_at, restored.undone
+                    );
+                    if !restored.conflicts.is_empty() {
+                        for path in &restored.conflicts {
+                            println!("changed since the sync, left alone: {}", path);
+                        }
+                        exit_with(format!(
+                            "{} entries were left alone, restore again once they are moved away",
+                            restored.conflicts.len()
+                        ));
+                    }
+                }
+                Ok(None) => println!("nothing to restore in {}", dir),
+                Err(err) => exit_with(err),
+            }
+        }
+        Some(Commands::Keygen { output }) => {
+            let public_key = release::generate_key(&output)
+                .unwrap_or_else(|err| exit_with(format!("{}: {}", output.display(), err)));
+            println!("secret key written to {}", output.display());
+            println!("public key: {}", public_key);
+        }
+        Some(Commands::Sign {
+            key,
+            target,
+            release_version,
+            binary,
+        }) => {
+            let sig_path = release::sign(&key, &binary, &target, &release_version)
+                .unwrap_or_else(|err| exit_with(err));
+            println!("signature written to {}", sig_path.display());
+        }
+        Some(Commands::Admin {
+            server,
+            admin_key,
+            command,
         }) => {
-            server_main(&listen, &dir, &auth_key).unwrap();
+            let server = server
+                .or(config.client.server)
+                .unwrap_or_else(|| exit_with("no server given, use -s or client.server"));
+            let admin_key = admin_key.or(config.server.admin_key).unwrap_or_else(|| {
+                exit_with("no admin key given, use --admin-key or server.admin_key")
+            });
+            let request = match command {
+                AdminCommand::Status => Request::AdminStatus,
+                AdminCommand::Clients => Request::AdminClients,
+                AdminCommand::Rescan => Request::AdminRescan,
+                AdminCommand::FlushCache => Request::AdminFlushCache,
+                AdminCommand::Reload => Request::AdminReload,
+            };
+            match admin(&server, &admin_key, &request).unwrap_or_else(|err| exit_with(err)) {
+                Response::Status(status) => {
+                    let scan = &status.scan;
+                    println!("version:    {}", status.version);
+                    println!(
+                        "uptime:     {}",
+                        human_duration(Duration::from_secs(status.uptime_secs))
+                    );
+                    println!("dir:        {}", status.dir);
+                    println!("generation: {}", status.generation);
+                    println!("files:      {} ({} distinct)", status.files, status.blobs);
+                    println!(
+                        "scan:       {}last at {} in {}ms, {} skipped{}",
+                        if scan.scanning { "running, " } else { "" },
+                        scan.last_scan_at,
+                        scan.last_duration_ms,
+                        scan.skipped,
+                        match &scan.last_error {
+                            Some(err) => format!(", last failed: {}", err),
+                            None => String::new(),
+                        }
+                    );
+                    println!(
+                        "cache:      {} files, {}, {} hits, {} misses",
+                        status.cache.entries,
+                        human_size(status.cache.bytes),
+                        status.cache.hits,
+                        status.cache.misses
+                    );
+                    println!("clients:    {}", status.clients);
+                }
+                Response::Clients(clients) => {
+                    for client in clients {
+                        println!(
+                            "{}\t{}\t{}\t{} requests\t{}",
+                            client.peer,
+                            if client.name.is_empty() {
+                                "-"
+                            } else {
+                                &client.name
+                            },
+                            human_duration(Duration::from_secs(client.connected_secs)),
+                            client.requests,
+                            client.current.as_deref().unwrap_or("idle")
+                        );
+                    }
+                }
+                Response::Done(message) => println!("{}", message),
+                Response::Error(err) => exit_with(err),
+                _ => exit_with("unexpected response"),
+            }
         }
+        Some(Commands::Config {
+            command: ConfigCommand::Check,
+        }) => match config_path {
+            Some(path) => println!("{}: ok", path.display()),
+            None => {
+                let paths: Vec<_> = config::default_paths()
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                exit_with(format!("no config file, looked for {}", paths.join(", ")));
+            }
+        },
         None => {
             println!("no command");
         }
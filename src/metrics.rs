@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::cache::CacheStats;
+
+/// Server counters, exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub clients: AtomicU64,
+    pub connections: AtomicU64,
+    /// Connections refused because the server was busy.
+    pub refused: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub watcher_events: AtomicU64,
+    pub scan_errors: AtomicU64,
+    requests: Mutex<BTreeMap<&'static str, u64>>,
+    scans: AtomicU64,
+    scan_micros: AtomicU64,
+    last_scan_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self, name: &'static str) {
+        *self.requests.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    pub fn scan(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        self.scans.fetch_add(1, Ordering::Relaxed);
+        self.scan_micros.fetch_add(micros, Ordering::Relaxed);
+        self.last_scan_micros.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, cache: &CacheStats) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+        let seconds = |counter: &AtomicU64| load(counter) / 1e6;
+        let metrics = [
+            (
+                "connected_clients",
+                "gauge",
+                "Client connections currently open.",
+                load(&self.clients),
+            ),
+            (
+                "connections_total",
+                "counter",
+                "Client connections accepted.",
+                load(&self.connections),
+            ),
+            (
+                "connections_refused_total",
+                "counter",
+                "Client connections refused because the server was busy.",
+                load(&self.refused),
+            ),
+            (
+                "auth_failures_total",
+                "counter",
+                "Auth requests with a wrong key.",
+                load(&self.auth_failures),
+            ),
+            (
+                "sent_bytes_total",
+                "counter",
+                "Bytes sent to clients.",
+                load(&self.bytes_sent),
+            ),
+            (
+                "received_bytes_total",
+                "counter",
+                "Bytes received from clients.",
+                load(&self.bytes_received),
+            ),
+            (
+                "cache_hits_total",
+                "counter",
+                "File cache hits.",
+                cache.hits as f64,
+            ),
+            (
+                "cache_misses_total",
+                "counter",
+                "File cache misses.",
+                cache.misses as f64,
+            ),
+            (
+                "cache_entries",
+                "gauge",
+                "Files held in the cache.",
+                cache.entries as f64,
+            ),
+            (
+                "cache_bytes",
+                "gauge",
+                "Compressed bytes held in the cache.",
+                cache.bytes as f64,
+            ),
+            (
+                "scans_total",
+                "counter",
+                "Scans of the served directory.",
+                load(&self.scans),
+            ),
+            (
+                "scan_errors_total",
+                "counter",
+                "Failed rescans of the served directory.",
+                load(&self.scan_errors),
+            ),
+            (
+                "scan_duration_seconds_total",
+                "counter",
+                "Time spent scanning the served directory.",
+                seconds(&self.scan_micros),
+            ),
+            (
+                "last_scan_duration_seconds",
+                "gauge",
+                "Duration of the last scan.",
+                seconds(&self.last_scan_micros),
+            ),
+            (
+                "watcher_events_total",
+                "counter",
+                "File system events seen by the watcher.",
+                load(&self.watcher_events),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP dirsync_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE dirsync_{} {}", name, kind);
+            let _ = writeln!(out, "dirsync_{} {}", name, value);
+        }
+        let _ = writeln!(out, "# HELP dirsync_requests_total Requests by type.");
+        let _ = writeln!(out, "# TYPE dirsync_requests_total counter");
+        for (name, count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "dirsync_requests_total{{type=\"{}\"}} {}", name, count);
+        }
+        out
+    }
+}
+
+/// A stream adding the bytes read and written to the metrics.
+pub struct Counted<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.metrics
+            .bytes_received
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.metrics
+            .bytes_sent
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Answers `GET /metrics` on `listener` with the output of `render`, one
+/// request at a time on a background thread.
+pub fn serve(listener: TcpListener, render: impl Fn() -> String + Send + 'static) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            if let Err(err) = respond(&mut stream, &render) {
+                debug!("metrics request failed: {}", err);
+            }
+        }
+    });
+}
+
+/// Longest request, with its headers, read before answering.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+fn respond(stream: &mut TcpStream, render: &impl Fn() -> String) -> std::io::Result<()> {
+    // one slow client holds up every scrape, so it gets little time and room
+    let timeout = Duration::from_secs(5);
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let started = Instant::now();
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        if started.elapsed() > timeout {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.request("GetFile");
+        metrics.request("GetFile");
+        metrics.request("Auth");
+        metrics.scan(Duration::from_millis(1500));
+        let cache = CacheStats {
+            hits: 3,
+            misses: 1,
+            entries: 1,
+            bytes: 42,
+        };
+        let out = metrics.render(&cache);
+        assert!(out.contains("dirsync_requests_total{type=\"GetFile\"} 2\n"));
+        assert!(out.contains("dirsync_requests_total{type=\"Auth\"} 1\n"));
+        assert!(out.contains("dirsync_cache_bytes 42\n"));
+        assert!(out.contains("dirsync_last_scan_duration_seconds 1.5\n"));
+        assert!(out.contains("# TYPE dirsync_connected_clients gauge\n"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(listener, || "dirsync_up 1\n".to_string());
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ndirsync_up 1\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+
+        // an endless request line is cut off instead of read into memory
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let _ = stream.write_all(&[b'a'; 4 * MAX_REQUEST as usize]);
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(get("/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
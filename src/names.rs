@@ -0,0 +1,405 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path};
+
+use serde::{Deserialize, Serialize};
+
+/// A file name as sent over the wire: the raw bytes of the name on unix,
+/// UTF-8 on Windows. Names which aren't valid UTF-8 survive the trip intact
+/// and are only mapped when the client creates them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RawName(pub Vec<u8>);
+
+impl RawName {
+    #[cfg(unix)]
+    pub fn from_os_str(name: &OsStr) -> Self {
+        use std::os::unix::ffi::OsStrExt;
+        RawName(name.as_bytes().to_vec())
+    }
+
+    /// Windows names are UTF-16, unpaired surrogates are replaced.
+    #[cfg(not(unix))]
+    pub fn from_os_str(name: &OsStr) -> Self {
+        RawName(name.to_string_lossy().into_owned().into_bytes())
+    }
+
+    /// The name as a local file name, `None` if this platform can't represent it.
+    #[cfg(unix)]
+    pub fn to_os_string(&self) -> Option<OsString> {
+        use std::os::unix::ffi::OsStrExt;
+        Some(OsStr::from_bytes(&self.0).to_os_string())
+    }
+
+    #[cfg(not(unix))]
+    pub fn to_os_string(&self) -> Option<OsString> {
+        std::str::from_utf8(&self.0).ok().map(OsString::from)
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0).into_owned()
+    }
+}
+
+impl std::fmt::Display for RawName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+impl From<&str> for RawName {
+    fn from(name: &str) -> Self {
+        RawName(name.as_bytes().to_vec())
+    }
+}
+
+/// The components of a relative path as raw bytes joined by `/`, the same on
+/// every platform.
+pub fn raw_path(path: &Path) -> Vec<u8> {
+    let mut result = Vec::new();
+    for component in path.components() {
+        if !result.is_empty() {
+            result.push(b'/');
+        }
+        result.extend(RawName::from_os_str(component.as_os_str()).0);
+    }
+    result
+}
+
+/// What the client does with a server name it can't create locally.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NamePolicy {
+    /// Percent-escape the offending characters.
+    #[default]
+    Escape,
+    /// Leave the entry, and everything below it, out of the sync.
+    Skip,
+    /// Abort the sync.
+    Fail,
+}
+
+impl std::str::FromStr for NamePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "escape" => Ok(NamePolicy::Escape),
+            "skip" => Ok(NamePolicy::Skip),
+            "fail" => Ok(NamePolicy::Fail),
+            _ => Err(format!("unknown name policy: {}", s)),
+        }
+    }
+}
+
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_windows_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    WINDOWS_RESERVED
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// Why `name` can't be used as a file name on unix, or on Windows if
+/// `windows` is set.
+fn invalid_reason(name: &[u8], windows: bool) -> Option<&'static str> {
+    if name.contains(&0) {
+        return Some("contains NUL");
+    }
+    if !windows {
+        return None;
+    }
+    let Ok(name) = std::str::from_utf8(name) else {
+        return Some("not valid UTF-8");
+    };
+    if name.chars().any(|c| c < ' ' || "<>:\"\\|?*".contains(c)) {
+        return Some("contains a character reserved on Windows");
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Some("ends with '.' or ' '");
+    }
+    if is_windows_reserved(name) {
+        return Some("reserved device name on Windows");
+    }
+    None
+}
+
+/// Whether `name` would leave its directory when joined to it, as `..` or a
+/// name with a `/` does. No file system has such names, so they are rejected
+/// whatever the policy. Windows separators and drive prefixes are legal names
+/// on unix and are escaped like other reserved characters.
+fn is_traversal(name: &[u8]) -> bool {
+    name.is_empty() || name == b"." || name == b".." || name.contains(&b'/')
+}
+
+/// Whether `path` is a single plain file name.
+fn is_single_component(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+/// Whether a link with `target`, in a directory `depth` levels below the
+/// synced root, stays inside the root.
+pub fn is_safe_link_target(depth: usize, target: &RawName) -> bool {
+    safe_link_target(depth, &target.0, cfg!(windows))
+}
+
+fn safe_link_target(mut depth: usize, target: &[u8], windows: bool) -> bool {
+    if target.is_empty() || target[0] == b'/' || target.contains(&0) {
+        return false;
+    }
+    for part in target.split(|b| *b == b'/') {
+        if windows && (part.contains(&b'\\') || part.contains(&b':')) {
+            return false;
+        }
+        match part {
+            b"" | b"." => {}
+            b".." => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            _ => depth += 1,
+        }
+    }
+    true
+}
+
+/// Whether `target` of the link at `link`, given as names from the synced
+/// root, goes through one of `links` before its last name. The file system
+/// follows such a link before the rest of the target, so a `..` after it
+/// doesn't lead where `is_safe_link_target` checked.
+pub fn target_crosses_link(link: &[&[u8]], target: &[u8], links: &HashSet<Vec<&[u8]>>) -> bool {
+    let mut path = link[..link.len().saturating_sub(1)].to_vec();
+    let parts: Vec<&[u8]> = target
+        .split(|b| *b == b'/')
+        .filter(|part| !part.is_empty() && *part != b".")
+        .collect();
+    for (i, part) in parts.iter().enumerate() {
+        if *part == b".." {
+            path.pop();
+        } else {
+            path.push(part);
+        }
+        if i + 1 < parts.len() && links.contains(&path) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Percent-escapes the bytes which make `name` invalid. The result is valid
+/// for unix and Windows alike.
+fn escape(name: &[u8]) -> String {
+    let mut result = String::new();
+    for chunk in name.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c < ' ' || "<>:\"/\\|?*%".contains(c) {
+                result.push_str(&format!("%{:02X}", c as u32));
+            } else {
+                result.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            result.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if result.is_empty() || result == "." || result == ".." {
+        result = result.replace('.', "%2E");
+        if result.is_empty() {
+            result.push_str("%00");
+        }
+    }
+    if result.ends_with('.') || result.ends_with(' ') {
+        let last = result.pop().unwrap();
+        result.push_str(&format!("%{:02X}", last as u32));
+    }
+    if is_windows_reserved(&result) {
+        let first = result.remove(0);
+        result.insert_str(0, &format!("%{:02X}", first as u32));
+    }
+    result
+}
+
+/// Maps server names to local names, applying the policy to names this
+/// platform can't represent and to names colliding with an earlier entry of
+/// the same directory. Every name which had to be changed or skipped gets a
+/// message in `warnings`.
+pub struct NameMapper {
+    policy: NamePolicy,
+    windows: bool,
+    case_insensitive: bool,
+    /// Names taken in the current directory, lowercased if case-insensitive.
+    taken: HashSet<String>,
+    pub warnings: Vec<String>,
+}
+
+impl NameMapper {
+    pub fn new(policy: NamePolicy) -> Self {
+        Self::for_platform(
+            policy,
+            cfg!(windows),
+            cfg!(any(windows, target_os = "macos")),
+        )
+    }
+
+    fn for_platform(policy: NamePolicy, windows: bool, case_insensitive: bool) -> Self {
+        Self {
+            policy,
+            windows,
+            case_insensitive,
+            taken: HashSet::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Starts a new directory, names only collide within one directory.
+    pub fn enter_dir(&mut self) {
+        self.taken.clear();
+    }
+
+    fn key(&self, name: &OsStr) -> String {
+        let name = name.to_string_lossy();
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name.into_owned()
+        }
+    }
+
+    /// Maps `name`, found in the server directory `dir`. Returns `None` if the
+    /// entry is to be skipped.
+    pub fn map(&mut self, dir: &str, name: &RawName) -> Result<Option<OsString>, String> {
+        if is_traversal(&name.0) {
+            return Err(format!("{}/{}: unsafe name from server", dir, name));
+        }
+        let mut local = match (invalid_reason(&name.0, self.windows), name.to_os_string()) {
+            (None, Some(local)) => local,
+            (reason, _) => {
+                let reason = reason.unwrap_or("not representable on this platform");
+                let message = format!("{}/{}: {}", dir, name, reason);
+                match self.policy {
+                    NamePolicy::Escape => {
+                        let escaped = OsString::from(escape(&name.0));
+                        self.warnings
+                            .push(format!("{}, saved as {:?}", message, escaped));
+                        escaped
+                    }
+                    NamePolicy::Skip => {
+                        self.warnings.push(format!("{}, skipped", message));
+                        return Ok(None);
+                    }
+                    NamePolicy::Fail => return Err(message),
+                }
+            }
+        };
+
+        if self.taken.contains(&self.key(&local)) {
+            let message = format!("{}/{}: collides with another name", dir, name);
+            match self.policy {
+                NamePolicy::Escape => {
+                    let base = local.clone();
+                    let mut n = 1;
+                    while self.taken.contains(&self.key(&local)) {
+                        local = base.clone();
+                        local.push(format!("~{}", n));
+                        n += 1;
+                    }
+                    self.warnings
+                        .push(format!("{}, saved as {:?}", message, local));
+                }
+                NamePolicy::Skip => {
+                    self.warnings.push(format!("{}, skipped", message));
+                    return Ok(None);
+                }
+                NamePolicy::Fail => return Err(message),
+            }
+        }
+        if !is_single_component(Path::new(&local)) {
+            return Err(format!("{}/{}: unsafe name from server", dir, name));
+        }
+        self.taken.insert(self.key(&local));
+        Ok(Some(local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_reason() {
+        assert_eq!(invalid_reason(b"a:b", false), None);
+        assert!(invalid_reason(b"a:b", true).is_some());
+        assert!(invalid_reason(b"name.", true).is_some());
+        assert!(invalid_reason(b"con.txt", true).is_some());
+        assert!(invalid_reason(b"bad\xff", true).is_some());
+        assert_eq!(invalid_reason(b"console", true), None);
+    }
+
+    #[test]
+    fn test_traversal() {
+        for name in [&b""[..], b".", b"..", b"../etc", b"/etc", b"a/b"] {
+            assert!(is_traversal(name), "{:?}", name);
+        }
+        assert!(!is_traversal(b"..\\x"));
+        assert!(!is_traversal(b"..."));
+
+        let mut mapper = NameMapper::for_platform(NamePolicy::Escape, true, true);
+        assert_eq!(mapper.map("", &"..\\x".into()), Ok(Some("..%5Cx".into())));
+        assert_eq!(mapper.map("", &"C:x".into()), Ok(Some("C%3Ax".into())));
+        let mut mapper = NameMapper::for_platform(NamePolicy::Escape, false, false);
+        assert!(mapper.map("", &"..".into()).is_err());
+        assert!(mapper.map("", &"../../etc".into()).is_err());
+    }
+
+    #[test]
+    fn test_safe_link_target() {
+        assert!(safe_link_target(0, b"a/b", false));
+        assert!(safe_link_target(1, b"../a", false));
+        assert!(safe_link_target(1, b"./b/../../a", false));
+        assert!(!safe_link_target(0, b"../a", false));
+        assert!(!safe_link_target(1, b"b/../../../a", false));
+        assert!(!safe_link_target(3, b"/etc/passwd", false));
+        assert!(safe_link_target(0, b"a\\b", false));
+        assert!(!safe_link_target(0, b"a\\..\\..\\b", true));
+        assert!(!safe_link_target(0, b"C:/x", true));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(b".."), "%2E%2E");
+        assert_eq!(escape(b"a:b?"), "a%3Ab%3F");
+        assert_eq!(escape(b"bad\xff"), "bad%FF");
+        assert_eq!(escape(b"name."), "name%2E");
+        assert_eq!(escape(b"CON"), "%43ON");
+        assert_eq!(invalid_reason(escape(b"nul.txt").as_bytes(), true), None);
+    }
+
+    #[test]
+    fn test_name_mapper() {
+        let mut mapper = NameMapper::for_platform(NamePolicy::Escape, true, true);
+        assert_eq!(mapper.map("", &"a:b".into()), Ok(Some("a%3Ab".into())));
+        assert_eq!(mapper.map("", &"Readme".into()), Ok(Some("Readme".into())));
+        assert_eq!(
+            mapper.map("", &"README".into()),
+            Ok(Some("README~1".into()))
+        );
+        assert_eq!(mapper.warnings.len(), 2);
+
+        let mut mapper = NameMapper::for_platform(NamePolicy::Skip, true, true);
+        assert_eq!(mapper.map("", &"a".into()), Ok(Some("a".into())));
+        assert_eq!(mapper.map("", &"A".into()), Ok(None));
+        mapper.enter_dir();
+        assert_eq!(mapper.map("", &"A".into()), Ok(Some("A".into())));
+
+        let mut mapper = NameMapper::for_platform(NamePolicy::Fail, false, false);
+        assert_eq!(mapper.map("", &"a:b".into()), Ok(Some("a:b".into())));
+        assert_eq!(mapper.map("", &"A:B".into()), Ok(Some("A:B".into())));
+        assert!(mapper.map("", &"a:b".into()).is_err());
+    }
+}
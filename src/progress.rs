@@ -0,0 +1,204 @@
+use std::io::{IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::human_size;
+
+/// How often the progress line is redrawn on a terminal.
+const TTY_INTERVAL: Duration = Duration::from_millis(200);
+/// How often a progress line is printed when stdout is not a terminal.
+const PLAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A stream adding the bytes read to a shared counter, so progress moves while
+/// a large response is still arriving.
+pub struct Metered<S> {
+    inner: S,
+    received: Arc<AtomicU64>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, received: Arc<AtomicU64>) -> Self {
+        Self { inner, received }
+    }
+}
+
+impl<S: Read> Read for Metered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Metered<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    done_bytes: u64,
+    done_files: usize,
+    /// Received bytes already counted in `done_bytes`, or before the start.
+    accounted: u64,
+    current: String,
+}
+
+/// Progress of downloading `total_files` files of `total_bytes` bytes.
+///
+/// Finished files count with their size. Bytes received for files still in
+/// flight count as they arrive, compressed, so the estimate is a little low
+/// until they finish.
+pub struct Progress {
+    total_bytes: u64,
+    total_files: usize,
+    received: Arc<AtomicU64>,
+    state: Mutex<State>,
+    started: Instant,
+}
+
+impl Progress {
+    /// `received` is the counter of the `Metered` streams the files arrive on.
+    pub fn new(total_bytes: u64, total_files: usize, received: Arc<AtomicU64>) -> Self {
+        let state = State {
+            accounted: received.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        Self {
+            total_bytes,
+            total_files,
+            received,
+            state: Mutex::new(state),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn start_file(&self, path: &str) {
+        self.state.lock().unwrap().current = path.to_string();
+    }
+
+    /// Counts a finished file of `size` bytes which took `wire_bytes` to receive.
+    pub fn file_done(&self, size: u64, wire_bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.done_bytes += size;
+        state.done_files += 1;
+        state.accounted += wire_bytes;
+    }
+
+    /// Bytes done so far, including the partly received files.
+    pub fn bytes(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        let in_flight = self
+            .received
+            .load(Ordering::Relaxed)
+            .saturating_sub(state.accounted);
+        (state.done_bytes + in_flight).min(self.total_bytes)
+    }
+
+    pub fn line(&self) -> String {
+        let bytes = self.bytes();
+        let elapsed = self.started.elapsed();
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let state = self.state.lock().unwrap();
+        let percent = match self.total_bytes {
+            0 => 100,
+            total => bytes * 100 / total,
+        };
+        let eta = if rate >= 1.0 {
+            eta((self.total_bytes - bytes) as f64 / rate)
+        } else {
+            "-".to_string()
+        };
+        format!(
+            "{} / {} ({}%), {}/{} files, {}/s, ETA {}, {}",
+            human_size(bytes),
+            human_size(self.total_bytes),
+            percent,
+            state.done_files,
+            self.total_files,
+            human_size(rate as u64),
+            eta,
+            state.current
+        )
+    }
+
+    /// Shows the progress on stdout until `done` is set: a line redrawn in
+    /// place on a terminal, otherwise a plain line every few seconds.
+    pub fn display(&self, done: &AtomicBool) {
+        let tty = std::io::stdout().is_terminal();
+        let interval = if tty { TTY_INTERVAL } else { PLAIN_INTERVAL };
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .unwrap_or(80usize);
+        let mut last = Instant::now();
+        while !done.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(50));
+            if last.elapsed() < interval {
+                continue;
+            }
+            last = Instant::now();
+            let line = self.line();
+            if tty {
+                let line: String = line.chars().take(width.saturating_sub(1)).collect();
+                print!("\r\x1b[2K{}", line);
+                let _ = std::io::stdout().flush();
+            } else {
+                println!("progress: {}", line);
+            }
+        }
+        if tty {
+            print!("\r\x1b[2K");
+            let _ = std::io::stdout().flush();
+        }
+    }
+}
+
+/// Whole seconds left, as `1h 5m`, `3m 20s` or `42s`.
+fn eta(seconds: f64) -> String {
+    let seconds = seconds.ceil() as u64;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let received = Arc::new(AtomicU64::new(100));
+        let progress = Progress::new(1000, 2, received.clone());
+        assert_eq!(progress.bytes(), 0);
+
+        // half of a compressed file has arrived
+        let mut stream = Metered::new(&[0u8; 50][..], received.clone());
+        std::io::copy(&mut stream, &mut std::io::sink()).unwrap();
+        assert_eq!(progress.bytes(), 50);
+
+        // it finishes at its full size, the other file is still missing
+        received.fetch_add(50, Ordering::Relaxed);
+        progress.file_done(600, 100);
+        assert_eq!(progress.bytes(), 600);
+        progress.start_file("b.txt");
+        assert!(progress.line().contains("1/2 files"));
+        assert!(progress.line().ends_with("b.txt"));
+
+        // never beyond the total
+        received.fetch_add(10_000, Ordering::Relaxed);
+        assert_eq!(progress.bytes(), 1000);
+
+        assert_eq!(eta(41.2), "42s");
+        assert_eq!(eta(200.0), "3m 20s");
+        assert_eq!(eta(3900.0), "1h 5m");
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Timelike;
+
+/// Reads and writes are split into chunks of this size, so transfers sharing a
+/// bucket interleave instead of waiting for each other's whole frames.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A token bucket limiting throughput to `rate` bytes per second, with a burst
+/// of one second worth of bytes. A rate of 0 means unlimited.
+pub struct TokenBucket {
+    rate: AtomicU64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Takes `n` tokens, sleeping until the bucket has paid them off.
+    pub fn take(&self, n: usize) {
+        let rate = self.rate() as f64;
+        if rate == 0.0 {
+            return;
+        }
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let (tokens, last) = *state;
+            let tokens = (tokens + now.duration_since(last).as_secs_f64() * rate).min(rate);
+            let tokens = tokens - n as f64;
+            *state = (tokens, now);
+            if tokens < 0.0 {
+                -tokens / rate
+            } else {
+                0.0
+            }
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+/// A stream whose reads and writes are limited by a set of token buckets,
+/// e.g. one shared by all connections and one for this connection only.
+pub struct Throttled<S> {
+    inner: S,
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, buckets: Vec<Arc<TokenBucket>>) -> Self {
+        Self { inner, buckets }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn throttle(&self, n: usize) {
+        for bucket in &self.buckets {
+            bucket.take(n);
+        }
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.throttle(n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE);
+        self.throttle(len);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Bandwidth limits by local time of day, parsed from a list like
+/// `08:00-18:00=1M,18:00-20:00=10M`. Windows may wrap around midnight.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// (start minute, end minute, rate) per window
+    windows: Vec<(u32, u32, u64)>,
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        fn parse_minute(s: &str) -> Result<u32, String> {
+            let (h, m) = s
+                .split_once(':')
+                .ok_or_else(|| format!("invalid time: {}", s))?;
+            let h: u32 = h.parse().map_err(|_| format!("invalid time: {}", s))?;
+            let m: u32 = m.parse().map_err(|_| format!("invalid time: {}", s))?;
+            if h > 24 || m > 59 || h * 60 + m > 24 * 60 {
+                return Err(format!("invalid time: {}", s));
+            }
+            Ok(h * 60 + m)
+        }
+
+        let mut windows = Vec::new();
+        for window in s.split(',').filter(|w| !w.trim().is_empty()) {
+            let (range, rate) = window
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("invalid schedule window: {}", window))?;
+            let (start, end) = range
+                .split_once('-')
+                .ok_or_else(|| format!("invalid schedule window: {}", window))?;
+            windows.push((
+                parse_minute(start)?,
+                parse_minute(end)?,
+                crate::common::parse_size(rate)?,
+            ));
+        }
+        Ok(Self { windows })
+    }
+
+    /// The rate of the first window containing `minute` (minutes since midnight).
+    pub fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|(start, end, _)| {
+                if start <= end {
+                    *start <= minute && minute < *end
+                } else {
+                    minute >= *start || minute < *end
+                }
+            })
+            .map(|(_, _, rate)| *rate)
+    }
+
+    /// The rate for the current local time, or `default` outside all windows.
+    pub fn current_rate(&self, default: u64) -> u64 {
+        let now = chrono::Local::now();
+        self.rate_at(now.hour() * 60 + now.minute())
+            .unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::parse("08:00-18:00=1M, 22:00-06:00=0").unwrap();
+        assert_eq!(schedule.rate_at(7 * 60 + 59), None);
+        assert_eq!(schedule.rate_at(8 * 60), Some(1024 * 1024));
+        assert_eq!(schedule.rate_at(18 * 60), None);
+        assert_eq!(schedule.rate_at(23 * 60), Some(0));
+        assert_eq!(schedule.rate_at(60), Some(0));
+        assert!(Schedule::parse("08:00=1M").is_err());
+        assert!(Schedule::parse("25:00-26:00=1M").is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(100 * 1024);
+        let clock = Instant::now();
+        // the first second worth of bytes is a burst, the next 50KB take ~0.5s
+        bucket.take(100 * 1024);
+        bucket.take(50 * 1024);
+        let elapsed = clock.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,430 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::fileinfo::get_hash;
+
+/// Version of this binary.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Set in the environment of a freshly updated client, so it never updates twice.
+pub const UPDATED_ENV: &str = "DIRSYNC_UPDATED";
+
+/// The platform this binary runs on, e.g. `x86_64-linux` or `aarch64-macos`.
+pub fn current_target() -> String {
+    format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+/// A client binary the server offers for one target.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub target: String,
+    pub version: String,
+    pub size: u64,
+    pub hash: String,
+    /// Ed25519 signature over target, version and content, empty when the
+    /// binary has no `.sig` file next to it.
+    pub signature: Vec<u8>,
+}
+
+impl Release {
+    /// Describes the binary at `path`, reading its signature from `<path>.sig`.
+    pub fn load(path: &Path, target: &str, version: &str) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        let signature = match fs::read_to_string(sig_path(path)) {
+            Ok(hex) => from_hex(hex.trim())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid signature"))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            target: target.to_string(),
+            version: version.to_string(),
+            size: content.len() as u64,
+            hash: get_hash(&content),
+            signature,
+        })
+    }
+
+    /// The running binary as a release for its own target.
+    pub fn current() -> io::Result<Self> {
+        Self::load(&std::env::current_exe()?, &current_target(), VERSION)
+    }
+
+    /// Checks that `content` is this release and carries a valid signature by `key`.
+    pub fn verify(&self, key: &VerifyingKey, content: &[u8]) -> Result<(), String> {
+        if content.len() as u64 != self.size || get_hash(content) != self.hash {
+            return Err("release content does not match its metadata".to_string());
+        }
+        let signature: [u8; 64] = self
+            .signature
+            .as_slice()
+            .try_into()
+            .map_err(|_| "release is not signed".to_string())?;
+        key.verify_strict(
+            &signed_message(&self.target, &self.version, content),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| "release signature is invalid".to_string())
+    }
+}
+
+/// Compares versions like semver does, e.g. `0.10.0 > 0.9.1` and
+/// `1.0.0 > 1.0.0-rc > 1.0.0-beta.2`. Dotted parts are compared numerically,
+/// parts which aren't numbers as text and after numbers; build metadata
+/// after `+` is ignored.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let a = a.split('+').next().unwrap_or_default();
+    let b = b.split('+').next().unwrap_or_default();
+    let (a_core, a_pre) = a.split_once('-').map_or((a, None), |(c, p)| (c, Some(p)));
+    let (b_core, b_pre) = b.split_once('-').map_or((b, None), |(c, p)| (c, Some(p)));
+    compare_parts(a_core, b_core).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        // a prerelease comes before its release
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(a), Some(b)) => compare_parts(a, b),
+    })
+}
+
+fn compare_parts(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        let ordering = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                _ => a.cmp(b),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// The release for `target` with `version`, or its latest release without one.
+pub fn select<'a>(
+    releases: &'a [Release],
+    target: &str,
+    version: Option<&str>,
+) -> Option<&'a Release> {
+    let mut candidates = releases.iter().filter(|release| release.target == target);
+    match version {
+        Some(version) => candidates.find(|release| release.version == version),
+        None => candidates.max_by(|a, b| compare_versions(&a.version, &b.version)),
+    }
+}
+
+/// Loads the releases in `dir`, laid out as `<version>/<target>/<binary>` with
+/// an optional `<binary>.sig` next to each binary. Directories which don't hold
+/// exactly one binary are returned as errors, the others are still loaded.
+pub fn load_releases(dir: &Path) -> io::Result<(Vec<Release>, Vec<String>)> {
+    let mut releases = Vec::new();
+    let mut errors = Vec::new();
+    for version_entry in fs::read_dir(dir)? {
+        let version_entry = version_entry?;
+        if !version_entry.file_type()?.is_dir() {
+            continue;
+        }
+        let version = version_entry.file_name().to_string_lossy().to_string();
+        for target_entry in fs::read_dir(version_entry.path())? {
+            let target_entry = target_entry?;
+            if !target_entry.file_type()?.is_dir() {
+                continue;
+            }
+            let target = target_entry.file_name().to_string_lossy().to_string();
+            let mut binaries = Vec::new();
+            for entry in fs::read_dir(target_entry.path())? {
+                let entry = entry?;
+                let is_sig = entry.path().extension().is_some_and(|ext| ext == "sig");
+                if entry.file_type()?.is_file() && !is_sig {
+                    binaries.push(entry.path());
+                }
+            }
+            match binaries.as_slice() {
+                [binary] => match Release::load(binary, &target, &version) {
+                    Ok(release) => releases.push(release),
+                    Err(err) => errors.push(format!("{}: {}", binary.display(), err)),
+                },
+                _ => errors.push(format!(
+                    "{}: expected one binary, found {}",
+                    target_entry.path().display(),
+                    binaries.len()
+                )),
+            }
+        }
+    }
+    sort_releases(&mut releases);
+    Ok((releases, errors))
+}
+
+/// Sorts releases by target, then by version.
+pub fn sort_releases(releases: &mut [Release]) {
+    releases.sort_by(|a, b| {
+        a.target
+            .cmp(&b.target)
+            .then_with(|| compare_versions(&a.version, &b.version))
+    });
+}
+
+/// Target and version are signed along with the content, so a valid binary
+/// can't be offered as another platform's or version's.
+fn signed_message(target: &str, version: &str, content: &[u8]) -> Vec<u8> {
+    let mut message = format!("dirsync release\n{}\n{}\n", target, version).into_bytes();
+    message.extend_from_slice(content);
+    message
+}
+
+pub fn sig_path(binary: &Path) -> PathBuf {
+    let mut path = binary.as_os_str().to_os_string();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn read_key<const N: usize>(s: &str) -> io::Result<[u8; N]> {
+    // a key is given in hex, or as a file holding the hex
+    let hex = match fs::read_to_string(s) {
+        Ok(content) => content.trim().to_string(),
+        // not hex, so it names a file
+        Err(err) if from_hex(s.trim()).is_none() => {
+            return Err(io::Error::new(err.kind(), format!("{}: {}", s, err)));
+        }
+        Err(_) => s.trim().to_string(),
+    };
+    from_hex(&hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid key: {}", s)))
+}
+
+/// Parses a public key given in hex or as a file holding the hex.
+pub fn parse_public_key(s: &str) -> Result<VerifyingKey, String> {
+    let key = read_key(s).map_err(|err| err.to_string())?;
+    VerifyingKey::from_bytes(&key).map_err(|_| format!("invalid public key: {}", s))
+}
+
+/// Writes a new secret key to `path` and returns its public key in hex.
+pub fn generate_key(path: &Path) -> io::Result<String> {
+    let key = SigningKey::generate(&mut rand_core::OsRng);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, to_hex(key.as_bytes()).as_bytes())?;
+    Ok(to_hex(key.verifying_key().as_bytes()))
+}
+
+/// Signs `binary` as a release for `target` and `version` with the secret key
+/// in `key_path`, writing the signature to `<binary>.sig`.
+pub fn sign(key_path: &Path, binary: &Path, target: &str, version: &str) -> io::Result<PathBuf> {
+    let secret = read_key(&key_path.to_string_lossy())?;
+    let key = SigningKey::from_bytes(&secret);
+    let content = fs::read(binary)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", binary.display(), err)))?;
+    let signature = key.sign(&signed_message(target, version, &content));
+    let path = sig_path(binary);
+    fs::write(&path, to_hex(&signature.to_bytes()))?;
+    Ok(path)
+}
+
+/// Replaces the binary at `exe` with `content`, keeping the old one as a
+/// timestamped `.bak` file whose path is returned.
+pub fn install(exe: &Path, content: &[u8]) -> io::Result<PathBuf> {
+    let name = exe.file_name().unwrap_or_default().to_string_lossy();
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let new_path = exe.with_file_name(format!("{}.new", name));
+    let backup = exe.with_file_name(format!("{}.{}.bak", name, millis));
+    fs::write(&new_path, content)?;
+    fs::set_permissions(&new_path, fs::metadata(exe)?.permissions())?;
+    // a running binary can be renamed on every platform, but not overwritten
+    fs::rename(exe, &backup)?;
+    if let Err(err) = fs::rename(&new_path, exe) {
+        let _ = fs::rename(&backup, exe);
+        return Err(err);
+    }
+    Ok(backup)
+}
+
+/// Puts the backup made by `install` back in place.
+pub fn rollback(exe: &Path, backup: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(exe);
+    fs::rename(backup, exe)
+}
+
+/// Runs `exe --version` and checks it starts and reports `version`.
+pub fn check_binary(exe: &Path, version: &str) -> Result<(), String> {
+    let output = Command::new(exe)
+        .arg("--version")
+        .output()
+        .map_err(|err| format!("new binary failed to start: {}", err))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || !stdout.split_whitespace().any(|word| word == version) {
+        return Err(format!(
+            "new binary failed to start: {} {}",
+            output.status,
+            stdout.trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0, 0xab, 0x10]));
+        assert_eq!(from_hex("0ab"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("0.10.0", "0.9.1"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.2.3", "1.2.3"), Ordering::Equal);
+        assert_eq!(
+            compare_versions("1.0.0-beta", "1.0.0-alpha"),
+            Ordering::Greater
+        );
+        assert_eq!(compare_versions("1.0.0-rc", "1.0.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0.0", "1.0.0-rc.1"), Ordering::Greater);
+        assert_eq!(
+            compare_versions("1.0.0-beta.11", "1.0.0-beta.2"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions("1.0.0-alpha.1", "1.0.0-alpha.beta"),
+            Ordering::Less
+        );
+        assert_eq!(compare_versions("1.0.0+build.5", "1.0.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_load_releases() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for (version, target) in [
+            ("0.10.0", "x86_64-linux"),
+            ("0.9.0", "x86_64-linux"),
+            ("0.9.0", "x86_64-windows"),
+        ] {
+            let dir = root.join(version).join(target);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("dirsync"), format!("{} {}", version, target)).unwrap();
+        }
+        fs::write(root.join("0.9.0/x86_64-windows/extra"), "").unwrap();
+        fs::create_dir_all(root.join("0.8.0/aarch64-macos")).unwrap();
+
+        let (releases, errors) = load_releases(root).unwrap();
+        let loaded: Vec<_> = releases
+            .iter()
+            .map(|r| (r.target.as_str(), r.version.as_str()))
+            .collect();
+        assert_eq!(
+            loaded,
+            vec![("x86_64-linux", "0.9.0"), ("x86_64-linux", "0.10.0")]
+        );
+        assert_eq!(releases[1].size, "0.10.0 x86_64-linux".len() as u64);
+        assert_eq!(
+            select(&releases, "x86_64-linux", None).map(|r| r.version.as_str()),
+            Some("0.10.0")
+        );
+        assert_eq!(
+            select(&releases, "x86_64-linux", Some("0.9.0")).map(|r| r.version.as_str()),
+            Some("0.9.0")
+        );
+        assert_eq!(select(&releases, "x86_64-linux", Some("0.8.0")), None);
+        assert_eq!(select(&releases, "x86_64-windows", None), None);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let key_path = root.join("key");
+        let binary = root.join("dirsync");
+        fs::write(&binary, "binary content").unwrap();
+
+        let public = parse_public_key(&generate_key(&key_path).unwrap()).unwrap();
+        let release = Release::load(&binary, "x86_64-linux", "1.0.0").unwrap();
+        assert_eq!(
+            release.verify(&public, b"binary content"),
+            Err("release is not signed".to_string())
+        );
+
+        sign(&key_path, &binary, "x86_64-linux", "1.0.0").unwrap();
+        let release = Release::load(&binary, "x86_64-linux", "1.0.0").unwrap();
+        assert_eq!(release.verify(&public, b"binary content"), Ok(()));
+        assert!(release.verify(&public, b"other content").is_err());
+
+        // the signature doesn't carry over to another target or version
+        let mut relabeled = release.clone();
+        relabeled.target = "x86_64-windows".to_string();
+        assert!(relabeled.verify(&public, b"binary content").is_err());
+        let mut relabeled = release.clone();
+        relabeled.version = "0.9.0".to_string();
+        assert!(relabeled.verify(&public, b"binary content").is_err());
+
+        let other = parse_public_key(&generate_key(&root.join("other")).unwrap()).unwrap();
+        assert!(release.verify(&other, b"binary content").is_err());
+
+        let missing = root.join("missing");
+        let err = sign(&missing, &binary, "x86_64-linux", "1.0.0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(parse_public_key(&missing.to_string_lossy())
+            .unwrap_err()
+            .contains("No such file"));
+        let err = generate_key(&key_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_install_and_rollback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let exe = root.join("dirsync");
+        fs::write(&exe, "old").unwrap();
+
+        let backup = install(&exe, b"new").unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), b"new");
+        assert_eq!(fs::read(&backup).unwrap(), b"old");
+        rollback(&exe, &backup).unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), b"old");
+        assert!(!backup.exists());
+    }
+}
@@ -0,0 +1,181 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::fileinfo::SkippedEntry;
+
+/// Format of the sync report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!("unknown report format: {}", s)),
+        }
+    }
+}
+
+/// A file the sync downloaded, or would download in a dry run.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    /// Time taken by the batch the file was downloaded in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkEntry {
+    pub path: String,
+    pub target: String,
+    /// `hardlink` or `symlink`.
+    pub kind: &'static str,
+}
+
+/// A file created from a local file with the same content instead of being
+/// downloaded.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReuseEntry {
+    pub path: String,
+    pub source: String,
+    /// `copy` or `hardlink`.
+    pub kind: &'static str,
+}
+
+/// A file moved from a local file which is not on the server any more,
+/// instead of being downloaded.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MoveEntry {
+    pub path: String,
+    pub source: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailedEntry {
+    pub path: String,
+    pub error: String,
+}
+
+/// What a sync planned and did. Paths are relative to the synced directory.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SyncReport {
+    pub server: String,
+    pub dir: String,
+    pub dry_run: bool,
+    /// Version of the server's history synced, none for its current files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Local time the sync started, in RFC 3339.
+    pub started_at: String,
+    pub duration_ms: u64,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Bytes of the files to download.
+    pub total_bytes: u64,
+    /// Files which didn't exist locally.
+    pub added: Vec<FileEntry>,
+    /// Files whose local content differed.
+    pub updated: Vec<FileEntry>,
+    /// Local entries removed, with the size of a file or of the files in a
+    /// directory. Only files have a hash.
+    pub deleted: Vec<FileEntry>,
+    pub created_dirs: Vec<String>,
+    pub links: Vec<LinkEntry>,
+    /// Files of `added` and `updated` which were created from local files.
+    pub reused: Vec<ReuseEntry>,
+    /// Files of `added` and `updated` which were moved from local files not
+    /// on the server, whose old paths are not listed in `deleted`.
+    pub moved: Vec<MoveEntry>,
+    /// Files changed locally since the last sync. They are only replaced with
+    /// `--local-changes overwrite`.
+    pub modified: Vec<String>,
+    /// Entries the server couldn't read, and links the client can't create.
+    pub skipped: Vec<SkippedEntry>,
+    pub failed: Vec<FailedEntry>,
+    pub warnings: Vec<String>,
+}
+
+impl SyncReport {
+    pub fn new(server: &str, dir: &str, dry_run: bool) -> Self {
+        Self {
+            server: server.to_string(),
+            dir: dir.to_string(),
+            dry_run,
+            started_at: chrono::Local::now().to_rfc3339(),
+            ..Default::default()
+        }
+    }
+
+    pub fn finish(&mut self, elapsed: Duration, error: Option<String>) {
+        self.duration_ms = elapsed.as_millis() as u64;
+        self.success = error.is_none();
+        self.error = error;
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Writes the report to `path`, or to stdout without one.
+    pub fn write(&self, format: ReportFormat, path: Option<&Path>) -> std::io::Result<()> {
+        let content = match format {
+            ReportFormat::Json => self.to_json(),
+        };
+        match path {
+            Some(path) => std::fs::write(path, content + "\n"),
+            None => {
+                println!("{}", content);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json() {
+        let mut report = SyncReport::new("host:9022", "dir", false);
+        report.added.push(FileEntry {
+            path: "a/b.txt".to_string(),
+            size: 3,
+            hash: "abc".to_string(),
+            duration_ms: Some(5),
+        });
+        report.deleted.push(FileEntry {
+            path: "old".to_string(),
+            size: 7,
+            hash: String::new(),
+            duration_ms: None,
+        });
+        report.failed.push(FailedEntry {
+            path: "c.txt".to_string(),
+            error: "NotFound".to_string(),
+        });
+        report.finish(
+            Duration::from_millis(1500),
+            Some("1 of 2 files failed".to_string()),
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["success"], false);
+        assert_eq!(json["duration_ms"], 1500);
+        assert_eq!(json["error"], "1 of 2 files failed");
+        assert_eq!(json["added"][0]["path"], "a/b.txt");
+        assert_eq!(json["added"][0]["duration_ms"], 5);
+        assert_eq!(json["deleted"][0]["size"], 7);
+        assert_eq!(json["failed"][0]["error"], "NotFound");
+        assert_eq!(json["updated"], serde_json::json!([]));
+    }
+}
@@ -1,29 +1,53 @@
-use crate::common::{read_file_as_compressed, Error, Frame, Request, Response};
+use crate::admin::{ClientStatus, ScanStatus, ServerStatus};
+use crate::cache::FileCache;
+use crate::common::{
+    compress_bytes, human_duration, human_size, read_file_as_compressed, Error, Frame, Request,
+    Response,
+};
 use crate::fileinfo::*;
+use crate::history::History;
+use crate::metrics::{self, Counted, Metrics};
+use crate::pool::ThreadPool;
+use crate::ratelimit::{Schedule, Throttled, TokenBucket};
+use crate::release::{self, Release};
+use crate::service::{self, PidFile};
 use notify::RecursiveMode;
 use notify_debouncer_mini::new_debouncer;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
 use std::path::PathBuf;
-// use std::time::Duration;
-use std::{net::ToSocketAddrs, sync::Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, trace, warn};
 
 struct UpdateInfo {
     target_dir: std::path::PathBuf,
     dir_info: DirInfo,
-    exe_hash: String,
     file_map: std::collections::HashMap<String, FileInfo>,
+    /// Path hash of one file for each content hash, so identical files are
+    /// served, compressed and cached as one blob.
+    blobs: HashMap<String, String>,
+    scan_report: ScanReport,
+    /// Counts the scans served so far.
+    generation: u64,
 }
 
 impl UpdateInfo {
-    pub fn new(target_dir: &str) -> Self {
+    pub fn new(target_dir: &str, links: LinkPolicy) -> std::io::Result<Self> {
         let target_path = std::path::Path::new(target_dir).to_path_buf();
-        let exe_path = std::env::current_exe().unwrap();
-        let exe_info = FileInfo::new(&exe_path).unwrap();
+        let (dir_info, scan_report) = DirInfo::scan(&target_path, links)?;
+        for skipped in &scan_report.skipped {
+            warn!(path = %skipped.path, reason = %skipped.reason, "scan skipped entry");
+        }
         let mut update_info = Self {
             target_dir: target_path.clone(),
-            dir_info: DirInfo::new(&target_path).unwrap(),
-            exe_hash: exe_info.hash,
+            dir_info,
             file_map: HashMap::new(),
+            blobs: HashMap::new(),
+            scan_report,
+            generation: 1,
         };
         update_info.dir_info.strip_root();
 
@@ -31,9 +55,7 @@ impl UpdateInfo {
         fn prepare_lookup_map(info: &UpdateInfo, map: &mut HashMap<String, FileInfo>) {
             fn traverse_dirinfo(map: &mut HashMap<String, FileInfo>, dir: &DirInfo) {
                 dir.files.iter().for_each(|f: &FileInfo| {
-                    let mut file_info_cloned = f.clone();
-                    file_info_cloned.path = (*dir).path.join(f.name.as_str());
-                    map.insert(f.path_hash.clone(), file_info_cloned);
+                    map.insert(f.path_hash.clone(), f.clone());
                 });
                 dir.subdirs.iter().for_each(|d| {
                     traverse_dirinfo(map, d);
@@ -43,166 +65,926 @@ impl UpdateInfo {
         }
         prepare_lookup_map(&update_info, &mut file_map);
         update_info.file_map = file_map;
-        update_info
+        for file_info in update_info.file_map.values() {
+            update_info
+                .blobs
+                .entry(file_info.hash.clone())
+                .or_insert_with(|| file_info.path_hash.clone());
+        }
+        Ok(update_info)
     }
 }
 
+/// Reads the settings again for `Request::AdminReload`.
+pub type Reload = Box<dyn Fn() -> Result<ServerOptions, String> + Send + Sync>;
+
+/// A connected client.
+struct Peer {
+    socket: TcpStream,
+    name: String,
+    connected: Instant,
+    requests: u64,
+    /// The request being answered.
+    current: Option<String>,
+}
+
 struct AppState {
-    update_info: std::sync::RwLock<UpdateInfo>,
-    file_cache: std::sync::RwLock<HashMap<String, Arc<Vec<u8>>>>,
+    update_info: RwLock<UpdateInfo>,
+    file_cache: FileCache,
+    /// Client binaries offered for self-update.
+    releases: RwLock<Vec<Release>>,
+    metrics: Arc<Metrics>,
+    /// The settings in effect, replaced on reload.
+    options: RwLock<ServerOptions>,
+    reload: Reload,
+    scan: Mutex<ScanStatus>,
+    /// Held while scanning, so the watcher and admins don't scan at once.
+    scan_lock: Mutex<()>,
+    /// Active connections, whose sockets are shut down for reading on exit
+    /// so idle connections stop waiting for requests while in-flight
+    /// responses finish.
+    connections: Mutex<HashMap<SocketAddr, Peer>>,
+    /// Shared by all connections.
+    bwlimit: Arc<TokenBucket>,
+    started: Instant,
+    history: Option<Mutex<History>>,
+}
+
+impl AppState {
+    fn update_peer(&self, peer: &SocketAddr, update: impl FnOnce(&mut Peer)) {
+        if let Some(peer) = self.connections.lock().unwrap().get_mut(peer) {
+            update(peer);
+        }
+    }
 }
 
+/// Answers `GetFileHash`, which only older clients send.
 fn handle_get_file_hash(app_state: Arc<AppState>, path_hash: &str) -> Result<Response, Error> {
     let update_info = app_state.update_info.read().unwrap();
-    if path_hash == "self" {
-        Ok(Response::FileHash(update_info.exe_hash.clone()))
-    } else {
-        match update_info.file_map.get(path_hash.into()) {
-            Some(file_info) => Ok(Response::FileHash(file_info.hash.clone())),
-            None => Err(Error::NotFound(path_hash.into())),
+    match update_info.file_map.get(path_hash) {
+        Some(file_info) => Ok(Response::FileHash(file_info.hash.clone())),
+        None => Err(Error::NotFound(path_hash.into())),
+    }
+}
+
+fn find_release(
+    app_state: &AppState,
+    target: &str,
+    version: Option<&str>,
+) -> Result<Release, Error> {
+    release::select(&app_state.releases.read().unwrap(), target, version)
+        .cloned()
+        .ok_or_else(|| {
+            Error::NotFound(format!("release {} for {}", version.unwrap_or("*"), target))
+        })
+}
+
+fn handle_get_release(
+    app_state: Arc<AppState>,
+    target: &str,
+    version: Option<&str>,
+) -> Result<Response, Error> {
+    Ok(Response::Release(find_release(
+        &app_state, target, version,
+    )?))
+}
+
+fn handle_get_release_file(
+    app_state: Arc<AppState>,
+    target: &str,
+    version: &str,
+) -> Result<Response, Error> {
+    let release = find_release(&app_state, target, Some(version))?;
+    let buf = app_state
+        .file_cache
+        .get_or_load(&format!("release:{}:{}", target, version), || {
+            read_file_as_compressed(&release.path).map_err(|e| Error::Io(e.to_string()))
+        })?;
+    Ok(Response::File(buf))
+}
+
+/// Serves the content with `hash` of the file with `path_hash`. The cache is
+/// keyed by content hash, so a file is only read from disk while it still has
+/// that content.
+fn handle_get_file(
+    app_state: Arc<AppState>,
+    peer: &SocketAddr,
+    path_hash: &str,
+    hash: &str,
+) -> Result<Response, Error> {
+    let (file_path, relative_path): (PathBuf, String) = {
+        let update_info = app_state.update_info.read().unwrap();
+        match update_info.file_map.get(path_hash) {
+            Some(file_info) if file_info.hash != hash => {
+                return Err(Error::Changed(file_info.path.display().to_string()))
+            }
+            Some(file_info) => {
+                let current = format!(
+                    "GetFile {} ({})",
+                    file_info.path.display(),
+                    human_size(file_info.size)
+                );
+                app_state.update_peer(peer, |peer| peer.current = Some(current));
+                (
+                    update_info.target_dir.join(&file_info.path),
+                    file_info.path.display().to_string(),
+                )
+            }
+            None => return Err(Error::NotFound(path_hash.into())),
+        }
+    };
+    let buf = app_state.file_cache.get_or_load(hash, || {
+        let content = std::fs::read(&file_path).map_err(|e| Error::Io(e.to_string()))?;
+        if get_hash(&content) != hash {
+            // modified since the scan, the watcher will rescan it
+            debug!(path = %file_path.display(), "file changed since the scan");
+            return Err(Error::Changed(relative_path));
+        }
+        Ok(compress_bytes(&content))
+    })?;
+    debug!(path = %file_path.display(), compressed = buf.len(), "file served");
+    Ok(Response::File(buf))
+}
+
+/// Serves the content with `hash` from any file having it, or else from the
+/// history.
+fn handle_get_blob(
+    app_state: Arc<AppState>,
+    peer: &SocketAddr,
+    hash: &str,
+) -> Result<Response, Error> {
+    let path_hash = app_state
+        .update_info
+        .read()
+        .unwrap()
+        .blobs
+        .get(hash)
+        .cloned();
+    if let Some(path_hash) = path_hash {
+        return handle_get_file(app_state, peer, &path_hash, hash);
+    }
+    let Some(history) = &app_state.history else {
+        return Err(Error::NotFound(hash.into()));
+    };
+    let buf = app_state.file_cache.get_or_load(hash, || {
+        history
+            .lock()
+            .unwrap()
+            .blob(hash)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => Error::NotFound(hash.into()),
+                _ => Error::Io(err.to_string()),
+            })
+    })?;
+    Ok(Response::File(buf))
+}
+
+fn handle_get_version(app_state: Arc<AppState>, version: u64) -> Result<Response, Error> {
+    let history = app_state
+        .history
+        .as_ref()
+        .ok_or_else(|| Error::NotFound("history is off".into()))?;
+    let history = history.lock().unwrap();
+    if !history.versions().iter().any(|v| v.version == version) {
+        return Err(Error::NotFound(format!("version {}", version)));
+    }
+    let dir_info = history
+        .dir_info(version)
+        .map_err(|err| Error::Io(err.to_string()))?;
+    Ok(Response::DirInfo(dir_info))
+}
+
+/// Settings of `server_main`.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub addr: String,
+    pub target_dir: String,
+    pub auth_key: String,
+    /// Key of connections allowed to send admin requests, none without one.
+    pub admin_key: Option<String>,
+    /// Connections beyond this limit are refused with a "server busy" error.
+    pub max_connections: usize,
+    /// Connections with no request for this long are closed.
+    pub idle_timeout: Duration,
+    /// Bytes per second for all connections together, 0 for unlimited.
+    pub bwlimit: u64,
+    /// Bytes per second for each connection, 0 for unlimited.
+    pub bwlimit_per_conn: u64,
+    /// Time-of-day windows overriding `bwlimit`.
+    pub bwlimit_schedule: Schedule,
+    /// How symbolic links in `target_dir` are served.
+    pub links: LinkPolicy,
+    /// Directory of client releases laid out as `<version>/<target>/<binary>`.
+    /// The server's own binary is always offered as well.
+    pub releases_dir: Option<String>,
+    /// Address of the HTTP listener serving Prometheus metrics at `/metrics`.
+    pub metrics_addr: Option<String>,
+    /// File the process id is written to while running.
+    pub pid_file: Option<PathBuf>,
+    /// How long in-flight transfers may take to finish on shutdown before
+    /// their connections are closed.
+    pub drain_timeout: Duration,
+    /// Directory keeping a version of the served directory for every scan
+    /// which changed it, no history without one.
+    pub history_dir: Option<PathBuf>,
+    /// Number of versions kept in the history.
+    pub history_keep: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            addr: ":9022".to_string(),
+            target_dir: ".".to_string(),
+            auth_key: "friday".to_string(),
+            admin_key: None,
+            max_connections: 64,
+            idle_timeout: Duration::from_secs(300),
+            bwlimit: 0,
+            bwlimit_per_conn: 0,
+            bwlimit_schedule: Schedule::default(),
+            links: LinkPolicy::default(),
+            releases_dir: None,
+            metrics_addr: None,
+            pid_file: None,
+            drain_timeout: Duration::from_secs(60),
+            history_dir: None,
+            history_keep: 10,
+        }
+    }
+}
+
+/// The server's own binary and the releases in `releases_dir`, which win over
+/// the own binary when they hold the same target and version.
+fn load_releases(releases_dir: Option<&str>) -> std::io::Result<Vec<Release>> {
+    let mut releases = Vec::new();
+    if let Some(dir) = releases_dir {
+        let (loaded, errors) = release::load_releases(std::path::Path::new(dir))?;
+        for err in errors {
+            warn!("release skipped {}", err);
         }
+        releases = loaded;
     }
+    let current = Release::current()?;
+    if release::select(&releases, &current.target, Some(&current.version)).is_none() {
+        releases.push(current);
+        release::sort_releases(&mut releases);
+    }
+    for release in &releases {
+        info!(
+            version = %release.version,
+            target = %release.target,
+            signed = !release.signature.is_empty(),
+            "offering client release"
+        );
+    }
+    Ok(releases)
+}
+
+fn auth_required(authed: Option<bool>, socket: &mut impl Write) -> std::io::Result<bool> {
+    if authed.is_none() || !authed.unwrap() {
+        Frame::from_response(&Response::Error("auth required".to_string())).write_to(socket)?;
+        return Ok(true);
+    }
+    Ok(false)
 }
 
-fn handle_get_file(app_state: Arc<AppState>, path_hash: &str) -> Result<Response, Error> {
-    let file_cache = app_state.file_cache.read().unwrap();
-    if !file_cache.contains_key(path_hash) {
-        let file_path: PathBuf;
-        if path_hash == "self" {
-            file_path = std::env::current_exe().unwrap();
-        } else {
-            let update_info = app_state.update_info.read().unwrap();
-            let v = update_info.file_map.get(path_hash.into());
-            if let Some(file_info) = v {
-                file_path = update_info.target_dir.join(&file_info.path);
-            } else {
-                return Err(Error::NotFound(path_hash.into()));
+fn handle_connection(
+    mut socket: Throttled<Counted<TcpStream>>,
+    app_state: Arc<AppState>,
+    peer: SocketAddr,
+) -> std::io::Result<()> {
+    let mut authed: Option<bool> = Option::None;
+    let mut admin = false;
+    loop {
+        app_state.update_peer(&peer, |peer| peer.current = None);
+        let frame = match Frame::read_from(&mut socket) {
+            Ok(frame) => frame,
+            // the client closed the connection, or it was shut down by the server
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let request = match frame.to_request() {
+            Ok(request) => request,
+            Err(err) => {
+                Frame::from_response(&Response::Error(format!("{:?}", err)))
+                    .write_to(&mut socket)?;
+                return Ok(());
+            }
+        };
+        debug!(request = request.name(), "request");
+        app_state.metrics.request(request.name());
+        app_state.update_peer(&peer, |peer| {
+            peer.requests += 1;
+            peer.current = Some(request.name().to_string());
+        });
+        match request {
+            Request::Auth(client_auth_key, client) => {
+                tracing::Span::current().record("client", client.as_str());
+                let (auth_key, admin_key) = {
+                    let options = app_state.options.read().unwrap();
+                    (options.auth_key.clone(), options.admin_key.clone())
+                };
+                admin = admin_key.is_some_and(|admin_key| client_auth_key == admin_key);
+                let ok = admin || client_auth_key == auth_key;
+                if !ok {
+                    warn!("auth failed");
+                    app_state
+                        .metrics
+                        .auth_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                authed = Some(ok);
+                app_state.update_peer(&peer, |peer| peer.name = client);
+                Frame::from_response(&Response::Auth(ok)).write_to(&mut socket)?;
+            }
+            Request::GetDirInfo(_) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response =
+                    Response::DirInfo(app_state.update_info.read().unwrap().dir_info.clone());
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::GetScanReport => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response =
+                    Response::ScanReport(app_state.update_info.read().unwrap().scan_report.clone());
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            // GetFileHash, GetFiles and GetFile are kept for older clients
+            Request::GetFileHash(path_hash) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = handle_get_file_hash(app_state.clone(), &path_hash)
+                    .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::GetFiles(files) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                for (path_hash, hash) in files {
+                    let response = handle_get_file(app_state.clone(), &peer, &path_hash, &hash)
+                        .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                    Frame::from_response(&response).write_to(&mut socket)?;
+                }
+            }
+            Request::GetFile(path_hash, hash) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = handle_get_file(app_state.clone(), &peer, &path_hash, &hash)
+                    .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::GetBlobs(hashes) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                for hash in hashes {
+                    let response = handle_get_blob(app_state.clone(), &peer, &hash)
+                        .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                    Frame::from_response(&response).write_to(&mut socket)?;
+                }
+            }
+            Request::GetBlob(hash) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = handle_get_blob(app_state.clone(), &peer, &hash)
+                    .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::ListVersions => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let versions = match &app_state.history {
+                    Some(history) => history.lock().unwrap().versions().to_vec(),
+                    None => Vec::new(),
+                };
+                Frame::from_response(&Response::Versions(versions)).write_to(&mut socket)?;
+            }
+            Request::GetVersion(version) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = handle_get_version(app_state.clone(), version)
+                    .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::ListReleases => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = Response::Releases(app_state.releases.read().unwrap().clone());
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::GetRelease(target, version) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = handle_get_release(app_state.clone(), &target, version.as_deref())
+                    .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            Request::GetReleaseFile(target, version) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = handle_get_release_file(app_state.clone(), &target, &version)
+                    .unwrap_or_else(|err| Response::Error(format!("{:?}", err)));
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+            request @ (Request::AdminStatus
+            | Request::AdminClients
+            | Request::AdminRescan
+            | Request::AdminFlushCache
+            | Request::AdminReload) => {
+                if auth_required(authed, &mut socket)? {
+                    return Ok(());
+                }
+                let response = if admin {
+                    info!(request = request.name(), "admin request");
+                    handle_admin(&app_state, &request)
+                } else {
+                    warn!(request = request.name(), "admin request refused");
+                    Response::Error("admin key required".to_string())
+                };
+                Frame::from_response(&response).write_to(&mut socket)?;
+            }
+        }
+    }
+}
+
+fn handle_admin(app_state: &AppState, request: &Request) -> Response {
+    match request {
+        Request::AdminStatus => Response::Status(status(app_state)),
+        Request::AdminClients => {
+            let connections = app_state.connections.lock().unwrap();
+            let mut clients: Vec<_> = connections
+                .iter()
+                .map(|(addr, peer)| ClientStatus {
+                    peer: addr.to_string(),
+                    name: peer.name.clone(),
+                    connected_secs: peer.connected.elapsed().as_secs(),
+                    requests: peer.requests,
+                    current: peer.current.clone(),
+                })
+                .collect();
+            clients.sort_by_key(|client| std::cmp::Reverse(client.connected_secs));
+            Response::Clients(clients)
+        }
+        Request::AdminRescan => match rescan(app_state) {
+            Ok(files) => {
+                let generation = app_state.update_info.read().unwrap().generation;
+                Response::Done(format!(
+                    "rescanned {} files, generation {}",
+                    files, generation
+                ))
+            }
+            Err(err) => Response::Error(err),
+        },
+        Request::AdminFlushCache => {
+            let stats = app_state.file_cache.stats();
+            app_state.file_cache.clear();
+            info!(entries = stats.entries, "file cache flushed");
+            Response::Done(format!(
+                "flushed {} files, {}",
+                stats.entries,
+                human_size(stats.bytes)
+            ))
+        }
+        Request::AdminReload => match reload(app_state) {
+            Ok(message) => Response::Done(message),
+            Err(err) => {
+                warn!("reload failed: {}", err);
+                Response::Error(err)
+            }
+        },
+        _ => Response::Error(format!("not an admin request: {}", request.name())),
+    }
+}
+
+fn status(app_state: &AppState) -> ServerStatus {
+    let update_info = app_state.update_info.read().unwrap();
+    ServerStatus {
+        version: release::VERSION.to_string(),
+        uptime_secs: app_state.started.elapsed().as_secs(),
+        dir: update_info.target_dir.display().to_string(),
+        generation: update_info.generation,
+        files: update_info.file_map.len(),
+        blobs: update_info.blobs.len(),
+        scan: app_state.scan.lock().unwrap().clone(),
+        cache: app_state.file_cache.stats(),
+        clients: app_state.connections.lock().unwrap().len(),
+    }
+}
+
+/// Scans the served directory and serves the result, unless the scan fails.
+/// Returns the number of files found.
+fn rescan(app_state: &AppState) -> Result<usize, String> {
+    let _scanning = app_state.scan_lock.lock().unwrap();
+    let (target_dir, links) = {
+        let options = app_state.options.read().unwrap();
+        (options.target_dir.clone(), options.links)
+    };
+    app_state.scan.lock().unwrap().scanning = true;
+    // scan without holding the lock, requests keep being served meanwhile
+    let clock = Instant::now();
+    let result = UpdateInfo::new(&target_dir, links);
+    let mut scan = app_state.scan.lock().unwrap();
+    scan.scanning = false;
+    let new_update_info = match result {
+        Ok(new_update_info) => new_update_info,
+        Err(err) => {
+            error!(dir = %target_dir, "rescan failed: {}", err);
+            app_state
+                .metrics
+                .scan_errors
+                .fetch_add(1, Ordering::Relaxed);
+            scan.last_error = Some(err.to_string());
+            return Err(format!("rescan failed: {}", err));
+        }
+    };
+    app_state.metrics.scan(clock.elapsed());
+    record_scan(&mut scan, &new_update_info, clock.elapsed());
+    drop(scan);
+    let files = new_update_info.file_map.len();
+    info!(
+        files,
+        elapsed = %human_duration(clock.elapsed()),
+        "rescanned"
+    );
+    let mut update_info = app_state.update_info.write().unwrap();
+    update_info.dir_info = new_update_info.dir_info;
+    update_info.file_map = new_update_info.file_map;
+    update_info.blobs = new_update_info.blobs;
+    update_info.scan_report = new_update_info.scan_report;
+    update_info.generation += 1;
+    // content no file has any more is dropped, the rest stays valid
+    let hashes: HashSet<&str> = update_info
+        .file_map
+        .values()
+        .map(|file_info| file_info.hash.as_str())
+        .collect();
+    app_state
+        .file_cache
+        .retain(|key| key.starts_with("release:") || hashes.contains(key));
+    drop(update_info);
+    record_history(app_state);
+    Ok(files)
+}
+
+/// Records the served file list as a new version in the history, if there
+/// is one. Failures are logged, the next scan records its version instead.
+fn record_history(app_state: &AppState) {
+    let Some(history) = &app_state.history else {
+        return;
+    };
+    let keep = app_state.options.read().unwrap().history_keep;
+    let update_info = app_state.update_info.read().unwrap();
+    let result = history.lock().unwrap().record(
+        &update_info.target_dir,
+        &update_info.dir_info,
+        update_info.file_map.values(),
+        keep,
+    );
+    match result {
+        Ok(Some(version)) => info!(version, "version recorded"),
+        Ok(None) => debug!("nothing changed, no version recorded"),
+        Err(err) => warn!("version not recorded: {}", err),
+    }
+}
+
+fn record_scan(scan: &mut ScanStatus, update_info: &UpdateInfo, elapsed: Duration) {
+    scan.last_scan_at = chrono::Local::now().to_rfc3339();
+    scan.last_duration_ms = elapsed.as_millis() as u64;
+    scan.last_error = None;
+    scan.skipped = update_info.scan_report.skipped.len();
+}
+
+/// Applies reloaded settings. The listen address, directory, connection limit
+/// and metrics address keep their values until a restart.
+fn reload(app_state: &AppState) -> Result<String, String> {
+    let new = (app_state.reload)()?;
+    let releases = load_releases(new.releases_dir.as_deref()).map_err(|err| err.to_string())?;
+    let mut restart = Vec::new();
+    let links_changed = {
+        let mut options = app_state.options.write().unwrap();
+        for (name, changed) in [
+            ("listen address", options.addr != new.addr),
+            ("dir", options.target_dir != new.target_dir),
+            (
+                "max connections",
+                options.max_connections != new.max_connections,
+            ),
+            ("metrics address", options.metrics_addr != new.metrics_addr),
+            ("history", options.history_dir != new.history_dir),
+        ] {
+            if changed {
+                warn!("{} changed, it takes a restart to apply", name);
+                restart.push(name);
             }
         }
-        drop(file_cache);
-        let mut file_cache = app_state.file_cache.write().unwrap();
-        let buf = read_file_as_compressed(file_path.as_path()).unwrap();
-        file_cache.insert(path_hash.to_string(), Arc::new(buf));
+        let links_changed = options.links != new.links;
+        *options = ServerOptions {
+            addr: std::mem::take(&mut options.addr),
+            target_dir: std::mem::take(&mut options.target_dir),
+            max_connections: options.max_connections,
+            metrics_addr: options.metrics_addr.take(),
+            pid_file: options.pid_file.take(),
+            history_dir: options.history_dir.take(),
+            ..new
+        };
+        links_changed
+    };
+    *app_state.releases.write().unwrap() = releases;
+    update_bwlimit(app_state);
+    if links_changed {
+        rescan(app_state)?;
+    }
+    info!("settings reloaded");
+    match restart.is_empty() {
+        true => Ok("reloaded".to_string()),
+        false => Ok(format!(
+            "reloaded, restart to change: {}",
+            restart.join(", ")
+        )),
     }
-    let file_cache = app_state.file_cache.read().unwrap();
-    Ok(Response::File(file_cache[path_hash].clone()))
 }
 
-pub fn server_main(addr: &str, target_dir: &str, auth_key: &str) -> std::io::Result<()> {
-    let ipv4_addrs: Vec<std::net::SocketAddr> =
-        addr.to_socket_addrs()?.filter(|x| x.is_ipv4()).collect();
+/// Sets the limit shared by all connections from the settings and the time
+/// of day.
+fn update_bwlimit(app_state: &AppState) {
+    let rate = {
+        let options = app_state.options.read().unwrap();
+        options.bwlimit_schedule.current_rate(options.bwlimit)
+    };
+    if rate != app_state.bwlimit.rate() {
+        match rate {
+            0 => info!("bandwidth limit removed"),
+            _ => info!("bandwidth limit set to {}/s", human_size(rate)),
+        }
+        app_state.bwlimit.set_rate(rate);
+    }
+}
 
-    if ipv4_addrs.len() == 0 {
+pub fn server_main(options: &ServerOptions, read_options: Reload) -> std::io::Result<()> {
+    let addr = options.addr.as_str();
+    let target_dir = options.target_dir.as_str();
+    let activated = service::activated_listener();
+    let ipv4_addrs: Vec<std::net::SocketAddr> = match activated {
+        Some(_) => Vec::new(),
+        None => addr.to_socket_addrs()?.filter(|x| x.is_ipv4()).collect(),
+    };
+
+    if activated.is_none() && ipv4_addrs.is_empty() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::AddrNotAvailable,
             "no ipv4 address",
         ));
     }
 
+    let _pid_file = options
+        .pid_file
+        .as_deref()
+        .map(PidFile::create)
+        .transpose()?;
+    let releases = load_releases(options.releases_dir.as_deref())?;
+    let metrics = Arc::new(Metrics::new());
+    let clock = std::time::Instant::now();
+    let update_info = UpdateInfo::new(target_dir, options.links)
+        .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", target_dir, err)))?;
+    metrics.scan(clock.elapsed());
+    let mut scan = ScanStatus::default();
+    record_scan(&mut scan, &update_info, clock.elapsed());
+    let history = options
+        .history_dir
+        .as_deref()
+        .map(History::open)
+        .transpose()?;
     let app_state = Arc::new(AppState {
-        update_info: std::sync::RwLock::new(UpdateInfo::new(target_dir)),
-        file_cache: std::sync::RwLock::new(HashMap::new()),
+        update_info: RwLock::new(update_info),
+        file_cache: FileCache::new(),
+        releases: RwLock::new(releases),
+        metrics,
+        options: RwLock::new(options.clone()),
+        reload: read_options,
+        scan: Mutex::new(scan),
+        scan_lock: Mutex::new(()),
+        connections: Mutex::new(HashMap::new()),
+        bwlimit: Arc::new(TokenBucket::new(options.bwlimit)),
+        started: Instant::now(),
+        history: history.map(Mutex::new),
     });
+    record_history(&app_state);
+
+    if let Some(metrics_addr) = &options.metrics_addr {
+        let listener = std::net::TcpListener::bind(metrics_addr)?;
+        info!("metrics listening on {}", listener.local_addr()?);
+        let app_state = app_state.clone();
+        metrics::serve(listener, move || {
+            app_state.metrics.render(&app_state.file_cache.stats())
+        });
+    }
 
     let app_state_clone = app_state.clone();
-    let target_dir_clone = target_dir.to_string();
+    let watch_dir = target_dir.to_string();
     // let (tx, mut rx) = tokio::sync::mpsc::channel(10);
     let mut debouncer = new_debouncer(
-        std::time::Duration::from_secs(10),
+        Duration::from_secs(10),
         None,
         move |res: notify_debouncer_mini::DebounceEventResult| {
             match res {
                 Ok(event) => {
-                    //println!("changed: {:?}", event);
+                    trace!(events = event.len(), "watcher events");
+                    app_state_clone
+                        .metrics
+                        .watcher_events
+                        .fetch_add(event.len() as u64, Ordering::Relaxed);
                     if let Some(_) = event.iter().find(|x| {
                         x.kind == notify_debouncer_mini::DebouncedEventKind::AnyContinuous
                     }) {
                         return;
                     }
-                    let mut update_info = app_state_clone.update_info.write().unwrap();
-                    let new_update_info = UpdateInfo::new(update_info.target_dir.to_str().unwrap());
-                    update_info.dir_info = new_update_info.dir_info;
-                    update_info.file_map = new_update_info.file_map;
-
-                    let mut file_cache = app_state_clone.file_cache.write().unwrap();
-                    file_cache.clear();
+                    // failures are logged and counted by rescan
+                    let _ = rescan(&app_state_clone);
                 }
-                Err(e) => println!("watch error: {:?}", e),
+                Err(e) => error!("watch error: {:?}", e),
             }
         },
     )
-    .unwrap();
+    .map_err(std::io::Error::other)?;
 
     debouncer
         .watcher()
         .watch(
-            std::path::Path::new(target_dir_clone.as_str()),
+            std::path::Path::new(watch_dir.as_str()),
             RecursiveMode::Recursive,
         )
-        .unwrap();
+        .map_err(std::io::Error::other)?;
+
+    // SIGTERM and SIGINT shut down, SIGHUP reloads the settings
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        signal_hook::flag::register(SIGTERM, shutdown.clone())?;
+        signal_hook::flag::register(SIGINT, shutdown.clone())?;
+        signal_hook::flag::register(SIGHUP, reload_requested.clone())?;
+    }
+    #[cfg(not(unix))]
+    {
+        let shutdown = shutdown.clone();
+        ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst))
+            .map_err(std::io::Error::other)?;
+    }
+
+    let listener = match activated {
+        Some(listener) => listener,
+        None => std::net::TcpListener::bind(ipv4_addrs[0])?,
+    };
+    info!("server listening on {}", listener.local_addr()?);
+    // poll accept so the shutdown flag is noticed
+    listener.set_nonblocking(true)?;
+
+    // follow the schedule, which a reload may add
+    let app_state_clone = app_state.clone();
+    std::thread::spawn(move || loop {
+        update_bwlimit(&app_state_clone);
+        std::thread::sleep(Duration::from_secs(30));
+    });
+
+    let mut pool = ThreadPool::new(options.max_connections);
+    let files = app_state.update_info.read().unwrap().file_map.len();
+    service::notify(&format!(
+        "READY=1\nSTATUS=serving {} files on {}",
+        files,
+        listener.local_addr()?
+    ));
+    let watchdog = service::watchdog_interval();
+    let mut watchdog_clock = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        // pinged from this loop, so a stuck server gets restarted
+        if watchdog.is_some_and(|interval| watchdog_clock.elapsed() >= interval) {
+            service::notify("WATCHDOG=1");
+            watchdog_clock = Instant::now();
+        }
+        if reload_requested.swap(false, Ordering::SeqCst) {
+            info!("reloading on SIGHUP");
+            let app_state = app_state.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = reload(&app_state) {
+                    warn!("reload failed: {}", err);
+                }
+            });
+        }
+        let (mut socket, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(err) => {
+                // e.g. out of file descriptors, retrying at once would spin
+                error!("accept error: {}", err);
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        let (idle_timeout, bwlimit_per_conn) = {
+            let options = app_state.options.read().unwrap();
+            (options.idle_timeout, options.bwlimit_per_conn)
+        };
+        // a client which stops reading is dropped like an idle one
+        let setup = |socket: &TcpStream| {
+            socket.set_nonblocking(false)?;
+            socket.set_nodelay(true)?;
+            socket.set_read_timeout(Some(idle_timeout))?;
+            socket.set_write_timeout(Some(idle_timeout))?;
+            socket.try_clone()
+        };
+        let peer_socket = match setup(&socket) {
+            Ok(peer_socket) => peer_socket,
+            Err(err) => {
+                warn!(%peer, "connection dropped: {}", err);
+                continue;
+            }
+        };
+        let mut connections = app_state.connections.lock().unwrap();
+        if connections.len() >= options.max_connections {
+            warn!(%peer, "connection refused: server busy");
+            app_state.metrics.refused.fetch_add(1, Ordering::Relaxed);
+            let _ = Frame::from_response(&Response::Error("server busy".to_string()))
+                .write_to(&mut socket);
+            continue;
+        }
+        connections.insert(
+            peer,
+            Peer {
+                socket: peer_socket,
+                name: String::new(),
+                connected: Instant::now(),
+                requests: 0,
+                current: None,
+            },
+        );
+        drop(connections);
 
-    println!("server listening on {}", ipv4_addrs[0]);
-    let listener = std::net::TcpListener::bind(ipv4_addrs[0])?;
-    loop {
-        let (mut socket, _) = listener.accept()?;
         let app_state = app_state.clone();
-        let auth_key = auth_key.to_string();
-        std::thread::spawn(move || -> Result<(), std::io::Error> {
-            let mut authed: Option<bool> = Option::None;
-            loop {
-                let frame = Frame::read_from(&mut socket)?;
-                let request = Request::decode(&frame.data);
-                match request {
-                    Request::Auth(client_auth_key) => {
-                        authed = Some(client_auth_key == auth_key);
-                        Frame::from_response(&Response::Auth(client_auth_key == auth_key))
-                            .write_to(&mut socket)?;
-                    }
-                    Request::GetDirInfo(_) => {
-                        if authed.is_none() || !authed.unwrap() {
-                            Frame::from_response(&Response::Error("auth required".to_string()))
-                                .write_to(&mut socket)?;
-                            return Ok(());
-                        }
-                        let response = Response::DirInfo(
-                            app_state.update_info.read().unwrap().dir_info.clone(),
-                        );
-                        Frame::from_response(&response).write_to(&mut socket)?;
-                    }
-                    Request::GetFileHash(path_hash) => {
-                        if authed.is_none() || !authed.unwrap() {
-                            Frame::from_response(&Response::Error("auth required".to_string()))
-                                .write_to(&mut socket)?;
-                            return Ok(());
-                        }
-                        match handle_get_file_hash(app_state.clone(), &path_hash) {
-                            Ok(response) => {
-                                Frame::from_response(&response).write_to(&mut socket)?;
-                            }
-                            Err(err) => {
-                                Frame::from_response(&Response::Error(format!("{:?}", err)))
-                                    .write_to(&mut socket);
-                            }
-                        }
-                    }
-                    Request::GetFile(path_hash) => {
-                        if authed.is_none() || !authed.unwrap() {
-                            Frame::from_response(&Response::Error("auth required".to_string()))
-                                .write_to(&mut socket)?;
-                            return Ok(());
-                        }
-                        match handle_get_file(app_state.clone(), &path_hash) {
-                            Ok(response) => {
-                                Frame::from_response(&response).write_to(&mut socket);
-                            }
-                            Err(err) => {
-                                Frame::from_response(&Response::Error(format!("{:?}", err)))
-                                    .write_to(&mut socket)?;
-                            }
-                        }
-                    }
+        let buckets = vec![
+            app_state.bwlimit.clone(),
+            Arc::new(TokenBucket::new(bwlimit_per_conn)),
+        ];
+        let metrics = app_state.metrics.clone();
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        metrics.clients.fetch_add(1, Ordering::Relaxed);
+        let socket = Throttled::new(Counted::new(socket, metrics.clone()), buckets);
+        pool.execute(move || {
+            let span = info_span!("connection", %peer, client = tracing::field::Empty);
+            let _enter = span.enter();
+            debug!("connection opened");
+            match handle_connection(socket, app_state.clone(), peer) {
+                Ok(()) => debug!("connection closed"),
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    info!("connection closed: idle timeout")
                 }
+                Err(err) => warn!("connection error: {}", err),
             }
+            metrics.clients.fetch_sub(1, Ordering::Relaxed);
+            app_state.connections.lock().unwrap().remove(&peer);
         });
     }
+
+    info!("shutting down");
+    service::notify("STOPPING=1");
+    for peer in app_state.connections.lock().unwrap().values() {
+        let _ = peer.socket.shutdown(Shutdown::Read);
+    }
+    let clock = Instant::now();
+    while !app_state.connections.lock().unwrap().is_empty() {
+        if clock.elapsed() >= options.drain_timeout {
+            let connections = app_state.connections.lock().unwrap();
+            warn!(
+                connections = connections.len(),
+                "drain timeout, closing connections"
+            );
+            for peer in connections.values() {
+                let _ = peer.socket.shutdown(Shutdown::Both);
+            }
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    pool.join();
+    Ok(())
 }
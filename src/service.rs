@@ -0,0 +1,228 @@
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+/// Sends a state like `READY=1` to the service manager, when started by one
+/// that asked for notifications through `NOTIFY_SOCKET`.
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = send_notify(Path::new(&socket), state) {
+        warn!("service notification failed: {}", err);
+    }
+}
+
+#[cfg(unix)]
+fn send_notify(socket: &Path, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    // a leading @ names a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket.to_str().and_then(|s| s.strip_prefix('@')) {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        datagram.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    datagram.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_notify(_socket: &Path, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How often the watchdog expects `WATCHDOG=1`, half the interval in
+/// `WATCHDOG_USEC`, when the watchdog is enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let for_us = match std::env::var("WATCHDOG_PID") {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => true,
+    };
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (for_us && usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// The listening socket passed by the service manager with socket
+/// activation (`LISTEN_FDS`), if any. Only the first socket is used.
+#[cfg(unix)]
+pub fn activated_listener() -> Option<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    // the first passed descriptor, after stdin, stdout and stderr
+    const LISTEN_FDS_START: i32 = 3;
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        warn!(
+            fds,
+            "socket activation passed several sockets, using the first"
+        );
+    }
+    debug!("using the socket passed by the service manager");
+    // safety: the service manager passed this descriptor to us, nothing else
+    // in the process owns it
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// A file holding the process id, removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Flags whose values are not written to unit files, which are world-readable.
+const SECRET_FLAGS: [&str; 2] = ["--auth-key", "--admin-key"];
+
+/// A systemd service unit running `dirsync` with `args` from `working_dir`.
+/// It waits for readiness, reloads with SIGHUP and enables the watchdog.
+/// Keys given in `args` are left out, the server reads them from its config file.
+pub fn unit(exe: &Path, args: &[String], working_dir: &Path, drain_timeout: Duration) -> String {
+    let mut exec_start = quote(&exe.to_string_lossy());
+    let mut left_out = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let secret = SECRET_FLAGS
+            .into_iter()
+            .find(|flag| arg == flag || arg.starts_with(&format!("{}=", flag)));
+        if let Some(flag) = secret {
+            if arg == flag {
+                args.next();
+            }
+            left_out.push(flag);
+            continue;
+        }
+        exec_start.push(' ');
+        exec_start.push_str(&quote(arg));
+    }
+    let note = if left_out.is_empty() {
+        String::new()
+    } else {
+        let keys: Vec<String> = left_out
+            .iter()
+            .map(|flag| format!("server.{}", flag[2..].replace('-', "_")))
+            .collect();
+        format!(
+            "# {} left out, set {} in the config file instead\n\n",
+            left_out.join(", "),
+            keys.join(", ")
+        )
+    };
+    format!(
+        "{}[Unit]
+Description=dirsync server
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=notify
+ExecStart={}
+ExecReload=/bin/kill -HUP $MAINPID
+WorkingDirectory={}
+WatchdogSec=30
+KillSignal=SIGTERM
+TimeoutStopSec={}
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+
+# For socket activation, add a dirsync.socket unit with the same name:
+#
+# [Socket]
+# ListenStream=9022
+#
+# [Install]
+# WantedBy=sockets.target
+",
+        note,
+        exec_start,
+        working_dir.to_string_lossy().replace('%', "%%"),
+        drain_timeout.as_secs() + 10
+    )
+}
+
+/// Quotes `arg` for an `ExecStart` line when it holds spaces or quotes, and
+/// escapes the `%` specifiers and `$` variables systemd would expand.
+fn quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit() {
+        let args = [
+            "server".to_string(),
+            "-d".to_string(),
+            "/srv/my data".to_string(),
+            "--auth-key".to_string(),
+            "secret".to_string(),
+            "--admin-key=secret".to_string(),
+            "--history".to_string(),
+            "/srv/100%$HOME".to_string(),
+        ];
+        let unit = unit(
+            Path::new("/usr/bin/dirsync"),
+            &args,
+            Path::new("/srv"),
+            Duration::from_secs(60),
+        );
+        assert!(unit.contains(
+            "\nExecStart=/usr/bin/dirsync server -d \"/srv/my data\" --history /srv/100%%$$HOME\n"
+        ));
+        assert!(!unit.contains("secret"));
+        assert!(unit.starts_with("# --auth-key, --admin-key left out"));
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains("\nTimeoutStopSec=70\n"));
+        assert!(unit.contains("\nWorkingDirectory=/srv\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send_notify(&path, "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
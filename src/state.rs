@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::tmp_path;
+
+/// File in the synced directory recording the content the last sync left.
+pub const STATE: &str = ".dirsync-state.json";
+
+/// The content of the synced files as the last sync left them, to tell the
+/// files changed locally since.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct State {
+    /// Local time of the sync, in RFC 3339.
+    pub synced_at: String,
+    /// Content hash by path relative to the synced directory.
+    pub files: BTreeMap<String, String>,
+}
+
+impl State {
+    /// Loads the state of the synced directory `root`, empty without one.
+    pub fn load(root: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(root.join(STATE)) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self, root: &Path) -> std::io::Result<()> {
+        let path = root.join(STATE);
+        let tmp_path = tmp_path(&path);
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(self).unwrap())?;
+        std::fs::rename(&tmp_path, &path)
+    }
+
+    /// Whether the file at `path`, now with content `hash`, changed since the
+    /// last sync. Files the last sync didn't leave are never modified.
+    pub fn is_modified(&self, path: &str, hash: &str) -> bool {
+        self.files.get(path).is_some_and(|synced| synced != hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        assert_eq!(State::load(root).unwrap(), State::default());
+
+        let mut state = State::default();
+        state.files.insert("a/b.txt".to_string(), "abc".to_string());
+        state.save(root).unwrap();
+        let state = State::load(root).unwrap();
+        assert!(!state.is_modified("a/b.txt", "abc"));
+        assert!(state.is_modified("a/b.txt", "def"));
+        assert!(!state.is_modified("c.txt", "def"));
+    }
+}